@@ -8,50 +8,43 @@ use android_sparse_image::{
 };
 use anyhow::{bail, Context};
 use clap::Parser;
+use fastboot_protocol::client::{DeviceMessage, Part, Progress, RebootTarget, Timeouts};
 use fastboot_protocol::nusb::NusbFastBoot;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
 
 #[derive(Parser)]
 enum Opts {
     GetVar { var: String },
     GetAllVars {},
     Flash { target: String, file: PathBuf },
-    Reboot,
+    Stage { file: PathBuf },
+    Boot { file: PathBuf },
+    Oem { command: Vec<String> },
+    Reboot { target: Option<String> },
+    Continue,
+    Powerdown,
 }
 
 async fn flash_raw<R>(
     fb: &mut NusbFastBoot,
     target: &str,
-    mut file: R,
-    file_size: u32,
+    file: R,
+    file_size: u64,
 ) -> anyhow::Result<()>
 where
-    R: AsyncRead + AsyncSeek + Unpin,
+    R: AsyncRead + Unpin,
 {
     println!("Uploading raw image directly");
-    let mut sender = fb.download(file_size).await?;
-    loop {
-        let left = sender.left();
-        if left == 0 {
-            break;
-        }
-        let buf = sender.get_mut_data(left as usize).await?;
-        file.read_exact(buf)
-            .await
-            .context("Failed to read from file")?;
-    }
-
-    sender.finish().await?;
-    println!("Flashing data");
+    fb.download_from_reader(file_size, file).await?;
     fb.flash(target).await?;
 
     Ok(())
 }
 
 async fn flash(fb: &mut NusbFastBoot, target: &str, file: &Path) -> anyhow::Result<()> {
-    let max_download = fb.get_var("max-download-size").await?;
-    let max_download = fastboot_protocol::protocol::parse_u32_hex(&max_download)
-        .with_context(|| anyhow::anyhow!("Failed to parse max download size: {max_download}"))?;
+    // Downloads are limited to 32 bits
+    let max_download = fb.max_download_size().await?;
+    let max_download = u32::try_from(max_download).unwrap_or(u32::MAX);
     println!("Max download size: {max_download}");
 
     let mut f = tokio::fs::File::open(file).await?;
@@ -69,6 +62,12 @@ async fn flash(fb: &mut NusbFastBoot, target: &str, file: &Path) -> anyhow::Resu
                 f.seek(SeekFrom::Current(chunk.data_size() as i64)).await?;
                 chunks.push(chunk);
             }
+            if fb
+                .fit_logical_partition(target, header.total_size() as u64)
+                .await?
+            {
+                println!("Resized logical partition {target}");
+            }
             split_image(&header, &chunks, max_download)?
         }
         Err(android_sparse_image::ParseError::UnknownMagic) => {
@@ -79,11 +78,14 @@ async fn flash(fb: &mut NusbFastBoot, target: &str, file: &Path) -> anyhow::Resu
                 .seek(SeekFrom::End(0))
                 .await
                 .context("Seek for determining file size")?;
+            if fb.fit_logical_partition(target, file_size).await? {
+                println!("Resized logical partition {target}");
+            }
             if file_size < max_download.into() {
                 f.seek(SeekFrom::Start(0))
                     .await
                     .context("Seeking back to the start")?;
-                return flash_raw(fb, target, f, file_size as u32).await;
+                return flash_raw(fb, target, f, file_size).await;
             }
             android_sparse_image::split::split_raw(file_size as usize, max_download)?
         }
@@ -92,8 +94,11 @@ async fn flash(fb: &mut NusbFastBoot, target: &str, file: &Path) -> anyhow::Resu
 
     println!("Flashing in {} parts", splits.len());
     for (i, split) in splits.iter().enumerate() {
-        println!("Downloading part {i}");
-        let mut sender = fb.download(split.sparse_size() as u32).await?;
+        fb.set_part(Some(Part {
+            index: i,
+            count: splits.len(),
+        }));
+        let mut sender = fb.download(split.sparse_size() as u64).await?;
 
         sender.extend_from_slice(&split.header.to_bytes()).await?;
         for chunk in &split.chunks {
@@ -101,19 +106,14 @@ async fn flash(fb: &mut NusbFastBoot, target: &str, file: &Path) -> anyhow::Resu
             f.seek(SeekFrom::Start(chunk.offset as u64))
                 .await
                 .context("Failed to seek input file")?;
-            let mut left = chunk.size;
-            while left > 0 {
-                let buf = sender.get_mut_data(left).await?;
-                left -= f
-                    .read_exact(buf)
-                    .await
-                    .context("Failed to read from file")?;
-            }
+            tokio::io::copy(&mut (&mut f).take(chunk.size as u64), &mut sender)
+                .await
+                .context("Failed to read from file")?;
         }
         sender.finish().await?;
-        println!("Flashing Part {i}");
         fb.flash(target).await?;
     }
+    fb.set_part(None);
 
     Ok(())
 }
@@ -137,6 +137,38 @@ async fn main() -> anyhow::Result<()> {
     );
 
     let mut fb = NusbFastBoot::from_info(&info)?;
+    fb.set_timeouts(Timeouts::recommended());
+    fb.set_progress_handler(|progress| match progress {
+        Progress::Downloading {
+            size,
+            part: Some(part),
+        } => println!(
+            "Downloading part {}/{} ({size} bytes)",
+            part.index + 1,
+            part.count
+        ),
+        Progress::Downloading { size, part: None } => println!("Downloading {size} bytes"),
+        Progress::Transfer(t) if t.acknowledged == t.size => {
+            println!("Sent {} bytes in {:.1?}", t.size, t.elapsed)
+        }
+        Progress::Transfer(_) => (),
+        Progress::Flashing {
+            partition,
+            part: Some(part),
+        } => println!(
+            "Flashing part {}/{} to {partition}",
+            part.index + 1,
+            part.count
+        ),
+        Progress::Flashing {
+            partition,
+            part: None,
+        } => println!("Flashing {partition}"),
+    });
+    fb.set_message_handler(|message| match message {
+        DeviceMessage::Info(i) => println!("(bootloader) {i}"),
+        DeviceMessage::Text(t) => print!("{t}"),
+    });
 
     match opts {
         Opts::GetVar { var } => {
@@ -150,7 +182,34 @@ async fn main() -> anyhow::Result<()> {
             }
         }
         Opts::Flash { target, file } => flash(&mut fb, &target, &file).await?,
-        Opts::Reboot => fb.reboot().await?,
+        Opts::Stage { file } => {
+            let f = tokio::fs::File::open(&file).await?;
+            let size = f.metadata().await?.len();
+            fb.stage(size, f).await?;
+        }
+        Opts::Boot { file } => {
+            let f = tokio::fs::File::open(&file).await?;
+            let size = f.metadata().await?.len();
+            fb.boot_image(size, f).await?;
+        }
+        Opts::Oem { command } => {
+            let r = fb.oem(&command.join(" ")).await?;
+            if !r.value.is_empty() {
+                println!("{}", r.value);
+            }
+        }
+        Opts::Reboot { target } => {
+            let target = match target.as_deref() {
+                None => RebootTarget::System,
+                Some("bootloader") => RebootTarget::Bootloader,
+                Some("recovery") => RebootTarget::Recovery,
+                Some("fastboot") => RebootTarget::Fastboot,
+                Some(other) => RebootTarget::Other(other.to_string()),
+            };
+            fb.reboot_to(&target).await?
+        }
+        Opts::Continue => fb.continue_boot().await?,
+        Opts::Powerdown => fb.powerdown().await?,
     }
 
     Ok(())
@@ -0,0 +1,27 @@
+#![no_main]
+
+use fastboot_protocol::protocol::{FastBootResponse, RawFastBootResponse};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let strict = FastBootResponse::from_bytes(data);
+    let lossy = FastBootResponse::from_bytes_lossy(data);
+
+    match RawFastBootResponse::from_bytes(data) {
+        Ok(raw) => {
+            assert_eq!(&data[4..], raw.payload);
+            let _ = raw.payload_lossy();
+        }
+        Err(e) => {
+            assert_eq!(strict.as_ref(), Err(&e));
+            assert_eq!(lossy.as_ref(), Err(&e));
+        }
+    }
+
+    // Valid responses survive serialization
+    if let Ok(response) = strict {
+        assert_eq!(lossy.as_ref(), Ok(&response));
+        let bytes = response.to_bytes();
+        assert_eq!(FastBootResponse::from_bytes(&bytes), Ok(response));
+    }
+});
@@ -0,0 +1,2749 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    io,
+    io::{Read, Write},
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tracing::warn;
+use tracing::{instrument, trace};
+
+use crate::protocol::FastBootResponse;
+use crate::protocol::{parse_size, FastBootCommand, FastBootResponseParseError};
+use crate::transport::FastbootTransport;
+use crate::variables::{parse_var_line, parse_yes_no, DeviceVariables};
+
+/// Fastboot communication errors
+#[derive(Debug, Error)]
+pub enum FastBootError {
+    #[error("Transport error: {0}")]
+    Transport(#[from] std::io::Error),
+    #[error("Fastboot client failure: {0}")]
+    FastbootFailed(String),
+    #[error("Unexpected fastboot response")]
+    FastbootUnexpectedReply,
+    #[error("Unknown fastboot response: {0}")]
+    FastbootParseError(#[from] FastBootResponseParseError),
+    #[error("Operation requires an unlocked device: {0}")]
+    DeviceLocked(String),
+    #[error("Operation not allowed during a snapshot update: {0}")]
+    SnapshotUpdateInProgress(String),
+    #[error("Download of {size} bytes exceeds the maximum download size of {max} bytes")]
+    DownloadTooLarge { size: u64, max: u64 },
+    #[error("Timed out after {0:?} waiting for the device")]
+    Timeout(Duration),
+    #[error("Device state unknown after an interrupted operation, resync required")]
+    ResyncRequired,
+    #[error("Device stopped responding after an interrupted operation, reconnect required")]
+    ReconnectRequired,
+}
+
+impl FastBootError {
+    /// Error for a FAIL response, recognizing failures due to a snapshot update being in
+    /// progress and, if `lock_related` is set, due to the device being locked
+    fn from_fail(reason: String, lock_related: bool) -> Self {
+        let lower = reason.to_ascii_lowercase();
+        if lock_related && mentions_locked(&lower) {
+            FastBootError::DeviceLocked(reason)
+        } else if lower.contains("snapshot update is in progress") {
+            FastBootError::SnapshotUpdateInProgress(reason)
+        } else {
+            FastBootError::FastbootFailed(reason)
+        }
+    }
+}
+
+/// Whether a (lowercase) FAIL reason states the device is locked
+///
+/// Phrases are matched on word boundaries, so mentions of an unlocked device are not matched
+fn mentions_locked(reason: &str) -> bool {
+    const PHRASES: &[&[&str]] = &[
+        &["locked", "state"],
+        &["lock", "state"],
+        &["device", "is", "locked"],
+        &["locked", "device"],
+    ];
+    let words: Vec<&str> = reason
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    PHRASES.iter().any(|phrase| {
+        words.windows(phrase.len()).enumerate().any(|(i, window)| {
+            // Exclude e.g. "un-locked state"
+            window == *phrase && (i == 0 || words[i - 1] != "un")
+        })
+    })
+}
+
+/// Whether the error indicates the device went away, e.g. because it booted or rebooted
+fn is_disconnect(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotConnected
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// A/B slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// Partition name suffix for the slot, e.g. `_a`
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Slot::A => "_a",
+            Slot::B => "_b",
+        }
+    }
+
+    /// The other slot
+    pub fn other(&self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+impl Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Slot::A => write!(f, "a"),
+            Slot::B => write!(f, "b"),
+        }
+    }
+}
+
+/// Error parsing a slot name
+#[derive(Debug, Error)]
+#[error("Invalid slot: {0}")]
+pub struct SlotParseError(String);
+
+impl FromStr for Slot {
+    type Err = SlotParseError;
+
+    /// Parse a slot name, both with (`_a`) and without (`a`) underscore
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix('_').unwrap_or(s) {
+            "a" => Ok(Slot::A),
+            "b" => Ok(Slot::B),
+            _ => Err(SlotParseError(s.to_string())),
+        }
+    }
+}
+
+/// Slot(s) to operate on for partitions that have slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotSelection {
+    /// The currently active slot
+    Current,
+    /// The slot that is currently not active
+    Other,
+    /// Both slots
+    All,
+    /// A specific slot
+    Slot(Slot),
+}
+
+/// Bootloader lock state as reported by the `unlocked` and `secure` variables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockState {
+    /// Whether the bootloader is unlocked, allowing flashing
+    pub unlocked: bool,
+    /// Whether the device enforces secure boot; `None` if the device doesn't report it
+    pub secure: Option<bool>,
+}
+
+/// Status of a virtual A/B snapshot update as reported by `snapshot-update-status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotUpdateStatus {
+    /// No snapshot update pending
+    None,
+    /// An update has been applied to snapshots but not merged yet
+    Snapshotted,
+    /// The snapshots are being merged
+    Merging,
+}
+
+impl SnapshotUpdateStatus {
+    /// Parse the value of the `snapshot-update-status` variable
+    pub(crate) fn from_var(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(SnapshotUpdateStatus::None),
+            "snapshotted" => Some(SnapshotUpdateStatus::Snapshotted),
+            "merging" => Some(SnapshotUpdateStatus::Merging),
+            _ => None,
+        }
+    }
+}
+
+/// Target to reboot the device into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebootTarget {
+    /// Normal system boot
+    System,
+    /// The bootloader (`reboot-bootloader`)
+    Bootloader,
+    /// Recovery (`reboot-recovery`)
+    Recovery,
+    /// Userspace fastboot (`reboot-fastboot`)
+    Fastboot,
+    /// Any other target, sent as `reboot:<target>`
+    Other(String),
+}
+
+/// Informational message sent by the device while executing a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceMessage {
+    /// INFO response
+    Info(String),
+    /// TEXT response
+    Text(String),
+}
+
+/// Timeouts used by [FastBoot] when waiting for the device
+///
+/// Timeouts apply to each individual packet rather than to a whole operation; As such INFO and
+/// TEXT messages sent by the device while executing a command reset the timeout. `None` disables
+/// the respective timeout, which is the default. Timeouts require a tokio runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Timeout for quick commands, e.g. `getvar`, and for sending commands
+    pub command: Option<Duration>,
+    /// Timeout for commands that can take a long time, e.g. `flash` and `erase`
+    pub long_command: Option<Duration>,
+    /// Timeout for each transfer during a DATA phase
+    pub data: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Timeouts suitable for most devices
+    pub fn recommended() -> Self {
+        Self {
+            command: Some(Duration::from_secs(10)),
+            long_command: Some(Duration::from_secs(10 * 60)),
+            data: Some(Duration::from_secs(30)),
+        }
+    }
+
+    fn for_command<S>(&self, cmd: &FastBootCommand<S>) -> Option<Duration> {
+        match cmd {
+            FastBootCommand::Flash(_)
+            | FastBootCommand::Erase(_)
+            | FastBootCommand::UpdateSuper(..)
+            | FastBootCommand::SnapshotUpdateMerge
+            | FastBootCommand::GsiWipe
+            // Locking and unlocking wipe the device and may wait for user confirmation
+            | FastBootCommand::FlashingLock
+            | FastBootCommand::FlashingUnlock
+            | FastBootCommand::FlashingLockCritical
+            | FastBootCommand::FlashingUnlockCritical
+            | FastBootCommand::Oem(_)
+            | FastBootCommand::Unknown(_) => self.long_command,
+            _ => self.command,
+        }
+    }
+}
+
+/// Time to wait for the device when resynchronizing if no command timeout is set
+const RESYNC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Run a transport operation, failing with [FastBootError::Timeout] if it doesn't finish in time
+async fn with_timeout<F, R>(timeout: Option<Duration>, op: F) -> Result<R, FastBootError>
+where
+    F: Future<Output = io::Result<R>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, op)
+            .await
+            .map_err(|_| FastBootError::Timeout(timeout))?
+            .map_err(FastBootError::from),
+        None => op.await.map_err(FastBootError::from),
+    }
+}
+
+/// Callback invoked for every [DeviceMessage] received, see [FastBoot::set_message_handler]
+pub type MessageHandler = Box<dyn FnMut(&DeviceMessage) + Send>;
+
+/// Part of a multi-part operation, e.g. a sparse image split over multiple downloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Part {
+    /// Index of the part, starting at 0
+    pub index: usize,
+    /// Total number of parts
+    pub count: usize,
+}
+
+/// Progress of the DATA phase of a download
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// Total size of the download
+    pub size: u64,
+    /// Data handed to the transport
+    pub queued: u64,
+    /// Data actually sent to the device
+    pub acknowledged: u64,
+    /// Time since the start of the DATA phase
+    pub elapsed: Duration,
+}
+
+impl TransferProgress {
+    /// Average throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.acknowledged as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Estimated time until all data has been sent, based on the average throughput
+    pub fn eta(&self) -> Option<Duration> {
+        let throughput = self.throughput();
+        if throughput > 0.0 {
+            let left = self.size.saturating_sub(self.acknowledged);
+            Some(Duration::from_secs_f64(left as f64 / throughput))
+        } else {
+            None
+        }
+    }
+}
+
+/// Progress event, see [FastBoot::set_progress_handler]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// The device accepted a download of `size` bytes
+    Downloading { size: u64, part: Option<Part> },
+    /// Data of the current download was queued or sent
+    Transfer(TransferProgress),
+    /// Flashing the downloaded data to `partition` started
+    Flashing {
+        partition: String,
+        part: Option<Part>,
+    },
+}
+
+/// Callback invoked for every [Progress] event, see [FastBoot::set_progress_handler]
+pub type ProgressHandler = Box<dyn FnMut(&Progress) + Send>;
+
+/// Output of a command
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandOutput {
+    /// Value of the final OKAY response
+    pub value: String,
+    /// INFO and TEXT messages sent by the device before the final response
+    pub messages: Vec<DeviceMessage>,
+}
+
+/// Fastboot client
+///
+/// Implements the fastboot command/response state machine on top of a [FastbootTransport]
+pub struct FastBoot<T> {
+    transport: T,
+    message_handler: Option<MessageHandler>,
+    progress_handler: Option<ProgressHandler>,
+    part: Option<Part>,
+    timeouts: Timeouts,
+    response_timeout: Option<Duration>,
+    // Whether a FAIL for the current command may be due to the device being locked
+    lock_related: bool,
+    needs_resync: bool,
+    // Whether a DATA response to the current command announces data sent by the device
+    uploading: bool,
+    // Data the device still expects in an interrupted download
+    data_left: u64,
+    // Data the device still sends in an interrupted upload
+    upload_left: u64,
+}
+
+impl<T: FastbootTransport> FastBoot<T> {
+    /// Create a fastboot client using the given transport
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            message_handler: None,
+            progress_handler: None,
+            part: None,
+            timeouts: Timeouts::default(),
+            response_timeout: None,
+            lock_related: false,
+            needs_resync: false,
+            uploading: false,
+            data_left: 0,
+            upload_left: 0,
+        }
+    }
+
+    /// Set a callback to observe the progress of downloads and flashes
+    ///
+    /// [Progress::Transfer] events are reported whenever data is handed to the transport and
+    /// whenever the transport finished sending data.
+    pub fn set_progress_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&Progress) + Send + 'static,
+    {
+        self.progress_handler = Some(Box::new(handler));
+    }
+
+    /// Remove the callback set by [Self::set_progress_handler]
+    pub fn clear_progress_handler(&mut self) {
+        self.progress_handler = None;
+    }
+
+    /// Set the part of a multi-part operation subsequent downloads and flashes belong to
+    ///
+    /// This is only used to annotate [Progress] events, e.g. when flashing a sparse image split
+    /// over multiple downloads. Should be reset to `None` once the operation is done.
+    pub fn set_part(&mut self, part: Option<Part>) {
+        self.part = part;
+    }
+
+    /// Set the timeouts used when waiting for the device
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Timeouts used when waiting for the device
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    /// Set a callback to observe INFO and TEXT messages as they are received
+    ///
+    /// The handler is called for messages sent during any command, e.g. to show progress of long
+    /// running operations like erasing a big partition. The INFO lines making up the variable list
+    /// of [Self::get_all_vars] are not reported.
+    pub fn set_message_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(&DeviceMessage) + Send + 'static,
+    {
+        self.message_handler = Some(Box::new(handler));
+    }
+
+    /// Remove the callback set by [Self::set_message_handler]
+    pub fn clear_message_handler(&mut self) {
+        self.message_handler = None;
+    }
+
+    /// Reference to the underlying transport
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Mutable reference to the underlying transport
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Consume the client returning the underlying transport
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    async fn send_command<S: Display>(
+        &mut self,
+        cmd: FastBootCommand<S>,
+    ) -> Result<(), FastBootError> {
+        if self.needs_resync {
+            return Err(FastBootError::ResyncRequired);
+        }
+        let mut out = vec![];
+        // Only fails if memory allocation fails
+        out.write_fmt(format_args!("{}", cmd)).unwrap();
+        trace!(
+            "Sending command: {}",
+            std::str::from_utf8(&out).unwrap_or("Invalid utf-8")
+        );
+        self.response_timeout = self.timeouts.for_command(&cmd);
+        // Until the final response is received, a response may still be on its way
+        self.needs_resync = true;
+        self.uploading = matches!(cmd, FastBootCommand::Upload);
+        self.lock_related = matches!(
+            cmd,
+            FastBootCommand::Flash(_)
+                | FastBootCommand::Erase(_)
+                | FastBootCommand::FlashingLock
+                | FastBootCommand::FlashingUnlock
+                | FastBootCommand::FlashingLockCritical
+                | FastBootCommand::FlashingUnlockCritical
+        );
+        let sent = with_timeout(self.timeouts.command, self.transport.send_packet(&out)).await;
+        self.check_timeout(sent)
+    }
+
+    /// Cancel in-flight transfers if `result` is a timeout, so they can't complete later on
+    ///
+    /// As the device may still respond to the timed out operation, a resync is required before
+    /// sending the next command
+    fn check_timeout<R>(&mut self, result: Result<R, FastBootError>) -> Result<R, FastBootError> {
+        if let Err(FastBootError::Timeout(_)) = result {
+            self.transport.cancel_transfers();
+            self.needs_resync = true;
+        }
+        result
+    }
+
+    /// Final response to a command received; The device is ready for the next command
+    fn command_done(&mut self) {
+        self.needs_resync = false;
+        self.data_left = 0;
+        self.upload_left = 0;
+    }
+
+    /// Error for a FAIL response to the current command
+    fn command_failed(&mut self, reason: String) -> FastBootError {
+        self.command_done();
+        FastBootError::from_fail(reason, self.lock_related)
+    }
+
+    /// Whether an operation was interrupted, requiring [Self::resync] before sending new
+    /// commands
+    ///
+    /// This is the case after a timeout or if a [DataDownload] or [DataUpload] was dropped (or
+    /// failed) before finishing.
+    pub fn needs_resync(&self) -> bool {
+        self.needs_resync
+    }
+
+    /// Get the device back into a state where it accepts commands after an interrupted operation
+    ///
+    /// The remainder of an interrupted download is sent as padding to finish the DATA phase;
+    /// The device will have received garbage, which is fine as long as the download isn't used.
+    /// Likewise the remainder of an interrupted upload is received and discarded. Responses to
+    /// the interrupted operation are then discarded, after which the device is probed with a
+    /// `getvar`. If the device doesn't respond to that or the transport fails
+    /// [FastBootError::ReconnectRequired] is returned. Requires a tokio runtime.
+    pub async fn resync(&mut self) -> Result<(), FastBootError> {
+        let result = self.try_resync().await;
+        if result.is_err() {
+            self.needs_resync = true;
+        }
+        result.map_err(|e| match e {
+            FastBootError::Timeout(_) | FastBootError::Transport(_) => {
+                FastBootError::ReconnectRequired
+            }
+            e => e,
+        })
+    }
+
+    async fn try_resync(&mut self) -> Result<(), FastBootError> {
+        self.transport.cancel_transfers();
+        let wait = self.timeouts.command.unwrap_or(RESYNC_TIMEOUT);
+        loop {
+            self.pad_download(wait).await?;
+            self.drain_upload(wait).await?;
+            let Ok(Ok(resp)) = tokio::time::timeout(wait, self.transport.receive_packet()).await
+            else {
+                break;
+            };
+            // A DATA response may only arrive after the command was abandoned
+            match FastBootResponse::from_bytes_lossy(&resp) {
+                Ok(FastBootResponse::Data(size)) if self.uploading => self.upload_left = size,
+                Ok(FastBootResponse::Data(size)) => self.data_left = size,
+                resp => trace!("Discarding response: {:?}", resp),
+            }
+        }
+
+        self.needs_resync = false;
+        self.send_command(FastBootCommand::GetVar("version"))
+            .await?;
+        self.response_timeout = Some(wait);
+        match self.collect_responses().await {
+            Ok(_) => Ok(()),
+            // A FAIL response still means the device accepts commands again
+            Err(
+                FastBootError::FastbootFailed(_)
+                | FastBootError::DeviceLocked(_)
+                | FastBootError::SnapshotUpdateInProgress(_),
+            ) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send the data the device still expects for an interrupted download
+    async fn pad_download(&mut self, timeout: Duration) -> Result<(), FastBootError> {
+        if self.data_left == 0 {
+            return Ok(());
+        }
+        trace!("Padding interrupted download with {} bytes", self.data_left);
+        let buffer_size = self.transport.data_buffer_size() as u64;
+        while self.data_left > 0 {
+            let len = self.data_left.min(buffer_size);
+            let padding = vec![0; len as usize];
+            let written = with_timeout(Some(timeout), self.transport.write_data(padding)).await;
+            self.check_timeout(written)?;
+            self.data_left -= len;
+        }
+        let flushed = with_timeout(Some(timeout), self.transport.flush_data()).await;
+        self.check_timeout(flushed)
+    }
+
+    /// Receive and discard the data the device still sends for an interrupted upload
+    async fn drain_upload(&mut self, timeout: Duration) -> Result<(), FastBootError> {
+        if self.upload_left == 0 {
+            return Ok(());
+        }
+        trace!(
+            "Discarding {} bytes of interrupted upload",
+            self.upload_left
+        );
+        while self.upload_left > 0 {
+            let max = usize::try_from(self.upload_left)
+                .unwrap_or(usize::MAX)
+                .min(self.transport.data_buffer_size());
+            let data = with_timeout(Some(timeout), self.transport.read_data(max)).await;
+            match self.check_timeout(data) {
+                Ok(data) => {
+                    self.upload_left = self.upload_left.saturating_sub(data.len() as u64);
+                }
+                // Part of the data may have been received by a cancelled read
+                Err(FastBootError::Timeout(_)) => self.upload_left = 0,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn read_response(&mut self) -> Result<FastBootResponse, FastBootError> {
+        let resp = with_timeout(self.response_timeout, self.transport.receive_packet()).await;
+        let resp = self.check_timeout(resp)?;
+        // Devices may send arbitrary bytes in e.g. INFO messages, so don't fail on those
+        Ok(FastBootResponse::from_bytes_lossy(&resp)?)
+    }
+
+    fn report_progress(&mut self, progress: Progress) {
+        trace!("Progress: {:?}", progress);
+        if let Some(handler) = &mut self.progress_handler {
+            handler(&progress);
+        }
+    }
+
+    fn report_message(&mut self, message: &DeviceMessage) {
+        trace!("Device message: {:?}", message);
+        if let Some(handler) = &mut self.message_handler {
+            handler(message);
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn handle_responses(&mut self) -> Result<String, FastBootError> {
+        self.collect_responses().await.map(|output| output.value)
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn collect_responses(&mut self) -> Result<CommandOutput, FastBootError> {
+        let mut messages = vec![];
+        loop {
+            let resp = self.read_response().await?;
+            trace!("Response: {:?}", resp);
+            match resp {
+                FastBootResponse::Info(i) => {
+                    let message = DeviceMessage::Info(i);
+                    self.report_message(&message);
+                    messages.push(message);
+                }
+                FastBootResponse::Text(t) => {
+                    let message = DeviceMessage::Text(t);
+                    self.report_message(&message);
+                    messages.push(message);
+                }
+                FastBootResponse::Data(_) => return Err(FastBootError::FastbootUnexpectedReply),
+                FastBootResponse::Okay(value) => {
+                    self.command_done();
+                    return Ok(CommandOutput { value, messages });
+                }
+                FastBootResponse::Fail(fail) => return Err(self.command_failed(fail)),
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all, err)]
+    async fn execute<S: Display>(
+        &mut self,
+        cmd: FastBootCommand<S>,
+    ) -> Result<String, FastBootError> {
+        self.send_command(cmd).await?;
+        self.handle_responses().await
+    }
+
+    /// Get the named variable
+    ///
+    /// The "all" variable is special; For that [Self::get_all_vars] should be used instead
+    pub async fn get_var(&mut self, var: &str) -> Result<String, FastBootError> {
+        let cmd = FastBootCommand::GetVar(var);
+        self.execute(cmd).await
+    }
+
+    /// Prepare a download of a given size
+    ///
+    /// Downloads of more than 4GiB need 64 bit download support on the device; Those are only
+    /// attempted if the device reports a large enough `max-download-size`, otherwise
+    /// [FastBootError::DownloadTooLarge] is returned.
+    ///
+    /// When successfull the [DataDownload] helper should be used to actually send the data
+    pub async fn download(&mut self, size: u64) -> Result<DataDownload<'_, T>, FastBootError> {
+        if size > u32::MAX as u64 {
+            let max = match self.max_download_size().await {
+                Ok(max) => max,
+                // Devices not reporting a max download size certainly don't do 64 bit downloads
+                Err(FastBootError::FastbootFailed(_)) => u32::MAX as u64,
+                Err(e) => return Err(e),
+            };
+            if size > max {
+                return Err(FastBootError::DownloadTooLarge { size, max });
+            }
+        }
+        let cmd = FastBootCommand::<&str>::Download(size);
+        self.send_command(cmd).await?;
+        loop {
+            let resp = self.read_response().await?;
+            match resp {
+                FastBootResponse::Info(i) => self.report_message(&DeviceMessage::Info(i)),
+                FastBootResponse::Text(t) => self.report_message(&DeviceMessage::Text(t)),
+                FastBootResponse::Data(size) => {
+                    // The device is in the DATA phase until all data has been sent
+                    self.data_left = size;
+                    let part = self.part;
+                    self.report_progress(Progress::Downloading { size, part });
+                    return Ok(DataDownload::new(self, size));
+                }
+                FastBootResponse::Okay(_) => {
+                    self.command_done();
+                    return Err(FastBootError::FastbootUnexpectedReply);
+                }
+                FastBootResponse::Fail(fail) => return Err(self.command_failed(fail)),
+            }
+        }
+    }
+
+    /// Upload data staged on the device (e.g. by an OEM command) to the host
+    ///
+    /// When successfull the [DataUpload] helper should be used to actually receive the data
+    pub async fn upload(&mut self) -> Result<DataUpload<'_, T>, FastBootError> {
+        let cmd = FastBootCommand::<&str>::Upload;
+        self.send_command(cmd).await?;
+        loop {
+            let resp = self.read_response().await?;
+            trace!("Response: {:?}", resp);
+            match resp {
+                FastBootResponse::Info(i) => self.report_message(&DeviceMessage::Info(i)),
+                FastBootResponse::Text(t) => self.report_message(&DeviceMessage::Text(t)),
+                FastBootResponse::Data(size) => {
+                    return Ok(DataUpload::new(self, size));
+                }
+                FastBootResponse::Okay(_) => {
+                    self.command_done();
+                    return Err(FastBootError::FastbootUnexpectedReply);
+                }
+                FastBootResponse::Fail(fail) => return Err(self.command_failed(fail)),
+            }
+        }
+    }
+
+    /// Stage `size` bytes read from `reader` on the device
+    ///
+    /// The staged data can be used by subsequent commands, typically [Self::oem] commands
+    pub async fn stage<R>(&mut self, size: u64, reader: R) -> Result<(), DownloadError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.download_from_reader(size, reader).await
+    }
+
+    /// Download `size` bytes read from `reader` to the device
+    ///
+    /// Exactly `size` bytes are read; The download fails if `reader` runs out of data before that.
+    pub async fn download_from_reader<R>(
+        &mut self,
+        size: u64,
+        mut reader: R,
+    ) -> Result<(), DownloadError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut download = self.download(size).await?;
+        while download.left() > 0 {
+            let left = usize::try_from(download.left()).unwrap_or(usize::MAX);
+            let buf = download.get_mut_data(left).await?;
+            reader.read_exact(buf).await.map_err(DownloadError::Read)?;
+        }
+        download.finish().await
+    }
+
+    /// Download `size` bytes read from a blocking `reader` to the device
+    ///
+    /// As [Self::download_from_reader], but for [std::io::Read] sources.
+    ///
+    /// The reads are done directly on the task running this future, blocking the async executor
+    /// for their duration. Only use this for sources that are quick to read from (e.g. local
+    /// files); Slow sources should be wrapped in an [AsyncRead] (e.g. reading on a blocking thread)
+    /// and passed to [Self::download_from_reader] instead.
+    pub async fn download_from_blocking_reader<R>(
+        &mut self,
+        size: u64,
+        mut reader: R,
+    ) -> Result<(), DownloadError>
+    where
+        R: Read,
+    {
+        let mut download = self.download(size).await?;
+        while download.left() > 0 {
+            let left = usize::try_from(download.left()).unwrap_or(usize::MAX);
+            let buf = download.get_mut_data(left).await?;
+            reader.read_exact(buf).map_err(DownloadError::Read)?;
+        }
+        download.finish().await
+    }
+
+    /// Execute an OEM specific command, e.g. `device-info` for `oem device-info`
+    pub async fn oem(&mut self, command: &str) -> Result<CommandOutput, FastBootError> {
+        let cmd = FastBootCommand::Oem(command);
+        self.send_command(cmd).await?;
+        self.collect_responses().await
+    }
+
+    /// Execute an arbitrary command
+    ///
+    /// The command is sent as is; This can be used for commands not otherwise supported by this
+    /// client. Commands starting a DATA phase are not supported.
+    pub async fn raw_command(&mut self, command: &str) -> Result<CommandOutput, FastBootError> {
+        let cmd = FastBootCommand::Unknown(command);
+        self.send_command(cmd).await?;
+        self.collect_responses().await
+    }
+
+    /// Execute a command after which the device is expected to go away
+    ///
+    /// Devices may disconnect before or after acknowledging the command; Losing the connection is
+    /// not treated as an error.
+    async fn execute_disconnecting<S: Display>(
+        &mut self,
+        cmd: FastBootCommand<S>,
+    ) -> Result<(), FastBootError> {
+        match self.execute(cmd).await {
+            Ok(v) => {
+                trace!("Command ok: {v}");
+                Ok(())
+            }
+            Err(FastBootError::Transport(e)) if is_disconnect(&e) => {
+                trace!("Device disconnected: {e}");
+                self.command_done();
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Boot the previously downloaded image
+    pub async fn boot(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::Boot;
+        self.execute_disconnecting(cmd).await
+    }
+
+    /// Continue the normal boot process of the device
+    pub async fn continue_boot(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::Continue;
+        self.execute_disconnecting(cmd).await
+    }
+
+    /// Power off the device
+    pub async fn powerdown(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::Powerdown;
+        self.execute_disconnecting(cmd).await
+    }
+
+    /// Download a boot image of `size` bytes read from `reader` and boot it without flashing
+    pub async fn boot_image<R>(&mut self, size: u64, reader: R) -> Result<(), DownloadError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        self.stage(size, reader).await?;
+        self.boot().await?;
+        Ok(())
+    }
+
+    /// Flash downloaded data to a given target partition
+    pub async fn flash(&mut self, target: &str) -> Result<(), FastBootError> {
+        let part = self.part;
+        self.report_progress(Progress::Flashing {
+            partition: target.to_string(),
+            part,
+        });
+        let cmd = FastBootCommand::Flash(target);
+        self.execute(cmd).await.map(|v| {
+            trace!("Flash ok: {v}");
+        })
+    }
+
+    /// Erasing the given target partition
+    pub async fn erase(&mut self, target: &str) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::Erase(target);
+        self.execute(cmd).await.map(|v| {
+            trace!("Erase ok: {v}");
+        })
+    }
+
+    /// Reboot the device
+    pub async fn reboot(&mut self) -> Result<(), FastBootError> {
+        self.reboot_to(&RebootTarget::System).await
+    }
+
+    /// Reboot the device to the bootloader
+    pub async fn reboot_bootloader(&mut self) -> Result<(), FastBootError> {
+        self.reboot_to(&RebootTarget::Bootloader).await
+    }
+
+    /// Reboot the device into the given target
+    pub async fn reboot_to(&mut self, target: &RebootTarget) -> Result<(), FastBootError> {
+        let cmd = match target {
+            RebootTarget::System => FastBootCommand::Reboot,
+            RebootTarget::Bootloader => FastBootCommand::RebootBootloader,
+            RebootTarget::Recovery => FastBootCommand::RebootRecovery,
+            RebootTarget::Fastboot => FastBootCommand::RebootFastboot,
+            RebootTarget::Other(target) => FastBootCommand::RebootTarget(target.as_str()),
+        };
+        self.execute_disconnecting(cmd).await
+    }
+
+    /// Maximum size of a single download
+    pub async fn max_download_size(&mut self) -> Result<u64, FastBootError> {
+        let size = self.get_var("max-download-size").await?;
+        parse_size(&size).map_err(|_| FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Size of a partition in bytes
+    pub async fn partition_size(&mut self, partition: &str) -> Result<u64, FastBootError> {
+        let size = self.get_var(&format!("partition-size:{partition}")).await?;
+        parse_size(&size).map_err(|_| FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Number of slots of the device, 0 if the device doesn't support slots
+    pub async fn slot_count(&mut self) -> Result<u32, FastBootError> {
+        let count = self.get_var("slot-count").await?;
+        count
+            .parse()
+            .map_err(|_| FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Currently active slot
+    pub async fn current_slot(&mut self) -> Result<Slot, FastBootError> {
+        let slot = self.get_var("current-slot").await?;
+        slot.parse()
+            .map_err(|_| FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Whether the slot has been marked as successfully booted
+    pub async fn slot_successful(&mut self, slot: Slot) -> Result<bool, FastBootError> {
+        let value = self.get_var(&format!("slot-successful:{slot}")).await?;
+        parse_yes_no(&value).ok_or(FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Whether the slot has been marked as unbootable
+    pub async fn slot_unbootable(&mut self, slot: Slot) -> Result<bool, FastBootError> {
+        let value = self.get_var(&format!("slot-unbootable:{slot}")).await?;
+        parse_yes_no(&value).ok_or(FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Mark the slot as active
+    pub async fn set_active(&mut self, slot: Slot) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::SetActive(slot);
+        self.execute(cmd).await.map(|v| {
+            trace!("Set active ok: {v}");
+        })
+    }
+
+    /// Whether the partition has slots
+    ///
+    /// Devices not knowing the partition or not supporting slots at all may fail the request;
+    /// That is treated as the partition not having slots.
+    pub async fn has_slot(&mut self, partition: &str) -> Result<bool, FastBootError> {
+        match self.get_var(&format!("has-slot:{partition}")).await {
+            Ok(value) => Ok(value == "yes"),
+            Err(FastBootError::FastbootFailed(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Resolve the partition names for `partition` for the given slot selection
+    ///
+    /// Partitions without slots are returned unchanged, otherwise the slot suffix is added for
+    /// each selected slot.
+    pub async fn slot_partitions(
+        &mut self,
+        partition: &str,
+        selection: SlotSelection,
+    ) -> Result<Vec<String>, FastBootError> {
+        if !self.has_slot(partition).await? {
+            return Ok(vec![partition.to_string()]);
+        }
+        let slots = match selection {
+            SlotSelection::Current => vec![self.current_slot().await?],
+            SlotSelection::Other => vec![self.current_slot().await?.other()],
+            SlotSelection::All => vec![Slot::A, Slot::B],
+            SlotSelection::Slot(slot) => vec![slot],
+        };
+        Ok(slots
+            .into_iter()
+            .map(|slot| format!("{partition}{}", slot.suffix()))
+            .collect())
+    }
+
+    /// Flash downloaded data to the selected slot(s) of the partition
+    ///
+    /// When flashing to [SlotSelection::All] the same downloaded data is flashed to both slots;
+    /// This relies on the device keeping the downloaded data around after a flash.
+    pub async fn flash_slot(
+        &mut self,
+        partition: &str,
+        selection: SlotSelection,
+    ) -> Result<(), FastBootError> {
+        for target in self.slot_partitions(partition, selection).await? {
+            self.flash(&target).await?;
+        }
+        Ok(())
+    }
+
+    /// Whether the partition is a logical partition in the super partition
+    ///
+    /// Devices without support for logical partitions may fail the request; That is treated as the
+    /// partition not being logical.
+    pub async fn is_logical(&mut self, partition: &str) -> Result<bool, FastBootError> {
+        match self.get_var(&format!("is-logical:{partition}")).await {
+            Ok(value) => Ok(value == "yes"),
+            Err(FastBootError::FastbootFailed(_)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create a logical partition of `size` bytes
+    pub async fn create_logical_partition(
+        &mut self,
+        partition: &str,
+        size: u64,
+    ) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::CreateLogicalPartition(partition, size);
+        self.execute(cmd).await.map(|v| {
+            trace!("Create logical partition ok: {v}");
+        })
+    }
+
+    /// Delete a logical partition
+    pub async fn delete_logical_partition(&mut self, partition: &str) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::DeleteLogicalPartition(partition);
+        self.execute(cmd).await.map(|v| {
+            trace!("Delete logical partition ok: {v}");
+        })
+    }
+
+    /// Resize a logical partition to `size` bytes
+    pub async fn resize_logical_partition(
+        &mut self,
+        partition: &str,
+        size: u64,
+    ) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::ResizeLogicalPartition(partition, size);
+        self.execute(cmd).await.map(|v| {
+            trace!("Resize logical partition ok: {v}");
+        })
+    }
+
+    /// Update the super partition metadata using the downloaded super image
+    ///
+    /// When `wipe` is set the existing metadata is discarded rather than merged
+    pub async fn update_super(&mut self, partition: &str, wipe: bool) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::UpdateSuper(partition, wipe);
+        self.execute(cmd).await.map(|v| {
+            trace!("Update super ok: {v}");
+        })
+    }
+
+    /// Resize the partition to fit an image of `size` bytes if it is a logical partition
+    ///
+    /// This should be called before downloading and flashing an image to a partition that may be
+    /// logical, as images can't be flashed to logical partitions that are too small. For sparse
+    /// images `size` is the expanded size of the image. Returns whether the partition was
+    /// resized.
+    pub async fn fit_logical_partition(
+        &mut self,
+        partition: &str,
+        size: u64,
+    ) -> Result<bool, FastBootError> {
+        if !self.is_logical(partition).await? {
+            return Ok(false);
+        }
+        self.resize_logical_partition(partition, size).await?;
+        Ok(true)
+    }
+
+    /// Status of the virtual A/B snapshot update
+    ///
+    /// While [SnapshotUpdateStatus::Merging] partitions that are part of the update can't be
+    /// flashed or erased until the update is cancelled using [Self::snapshot_update_cancel]
+    pub async fn snapshot_update_status(&mut self) -> Result<SnapshotUpdateStatus, FastBootError> {
+        let status = self.get_var("snapshot-update-status").await?;
+        SnapshotUpdateStatus::from_var(&status).ok_or(FastBootError::FastbootUnexpectedReply)
+    }
+
+    /// Cancel a pending snapshot update
+    pub async fn snapshot_update_cancel(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::SnapshotUpdateCancel;
+        self.execute(cmd).await.map(|v| {
+            trace!("Snapshot update cancel ok: {v}");
+        })
+    }
+
+    /// Merge a pending snapshot update
+    pub async fn snapshot_update_merge(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::SnapshotUpdateMerge;
+        self.execute(cmd).await.map(|v| {
+            trace!("Snapshot update merge ok: {v}");
+        })
+    }
+
+    /// Wipe the installed GSI
+    pub async fn gsi_wipe(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::GsiWipe;
+        self.execute(cmd).await.map(|v| {
+            trace!("GSI wipe ok: {v}");
+        })
+    }
+
+    /// Disable the installed GSI
+    pub async fn gsi_disable(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::GsiDisable;
+        self.execute(cmd).await.map(|v| {
+            trace!("GSI disable ok: {v}");
+        })
+    }
+
+    /// Query the status of the GSI, which is reported in the device messages
+    pub async fn gsi_status(&mut self) -> Result<CommandOutput, FastBootError> {
+        let cmd = FastBootCommand::<&str>::GsiStatus;
+        self.send_command(cmd).await?;
+        self.collect_responses().await
+    }
+
+    /// Current bootloader lock state
+    pub async fn lock_state(&mut self) -> Result<LockState, FastBootError> {
+        let unlocked = self.get_var("unlocked").await?;
+        let unlocked = parse_yes_no(&unlocked).ok_or(FastBootError::FastbootUnexpectedReply)?;
+        let secure = match self.get_var("secure").await {
+            Ok(secure) => {
+                Some(parse_yes_no(&secure).ok_or(FastBootError::FastbootUnexpectedReply)?)
+            }
+            Err(FastBootError::FastbootFailed(_)) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(LockState { unlocked, secure })
+    }
+
+    /// Lock the bootloader, preventing flashing (`flashing lock`)
+    pub async fn flashing_lock(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::FlashingLock;
+        self.execute(cmd).await.map(|v| {
+            trace!("Lock ok: {v}");
+        })
+    }
+
+    /// Unlock the bootloader, allowing flashing (`flashing unlock`)
+    ///
+    /// Most devices ask the user for confirmation before responding
+    pub async fn flashing_unlock(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::FlashingUnlock;
+        self.execute(cmd).await.map(|v| {
+            trace!("Unlock ok: {v}");
+        })
+    }
+
+    /// Lock flashing of bootloader critical partitions (`flashing lock_critical`)
+    pub async fn flashing_lock_critical(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::FlashingLockCritical;
+        self.execute(cmd).await.map(|v| {
+            trace!("Lock critical ok: {v}");
+        })
+    }
+
+    /// Unlock flashing of bootloader critical partitions (`flashing unlock_critical`)
+    pub async fn flashing_unlock_critical(&mut self) -> Result<(), FastBootError> {
+        let cmd = FastBootCommand::<&str>::FlashingUnlockCritical;
+        self.execute(cmd).await.map(|v| {
+            trace!("Unlock critical ok: {v}");
+        })
+    }
+
+    /// Whether the bootloader may be unlocked (`flashing get_unlock_ability`)
+    pub async fn get_unlock_ability(&mut self) -> Result<bool, FastBootError> {
+        let cmd = FastBootCommand::<&str>::FlashingGetUnlockAbility;
+        self.send_command(cmd).await?;
+        let output = self.collect_responses().await?;
+        // The ability is reported as "get_unlock_ability: <0|1>", typically in an INFO message
+        let ability = output
+            .messages
+            .iter()
+            .map(|m| match m {
+                DeviceMessage::Info(s) | DeviceMessage::Text(s) => s,
+            })
+            .chain(std::iter::once(&output.value))
+            .find_map(|s| s.trim().strip_prefix("get_unlock_ability:"))
+            .ok_or(FastBootError::FastbootUnexpectedReply)?;
+        match ability.trim() {
+            "1" => Ok(true),
+            "0" => Ok(false),
+            _ => Err(FastBootError::FastbootUnexpectedReply),
+        }
+    }
+
+    /// Retrieve all variables as a typed [DeviceVariables]
+    pub async fn get_device_variables(&mut self) -> Result<DeviceVariables, FastBootError> {
+        let vars = self.get_all_vars().await?;
+        Ok(DeviceVariables::from_vars(vars))
+    }
+
+    /// Retrieve all variables
+    pub async fn get_all_vars(&mut self) -> Result<HashMap<String, String>, FastBootError> {
+        let cmd = FastBootCommand::GetVar("all");
+        self.send_command(cmd).await?;
+        let mut vars = HashMap::new();
+        loop {
+            let resp = self.read_response().await?;
+            trace!("Response: {:?}", resp);
+            match resp {
+                FastBootResponse::Info(i) => {
+                    let Some((key, value)) = parse_var_line(&i) else {
+                        warn!("Failed to parse variable: {i}");
+                        continue;
+                    };
+                    vars.insert(key.to_string(), value.to_string());
+                }
+                FastBootResponse::Text(t) => self.report_message(&DeviceMessage::Text(t)),
+                FastBootResponse::Data(_) => return Err(FastBootError::FastbootUnexpectedReply),
+                FastBootResponse::Okay(_) => {
+                    self.command_done();
+                    return Ok(vars);
+                }
+                FastBootResponse::Fail(fail) => return Err(self.command_failed(fail)),
+            }
+        }
+    }
+}
+
+/// Error during data download
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("Trying to complete while nothing was Queued")]
+    NothingQueued,
+    #[error("Incorrect data length: expected {expected}, got {actual}")]
+    IncorrectDataLength { actual: u64, expected: u64 },
+    #[error("Failed to read data to download: {0}")]
+    Read(std::io::Error),
+    #[error(transparent)]
+    FastBoot(#[from] FastBootError),
+}
+
+/// Write of a buffer to the transport that is in progress; The future holds on to the client until
+/// the write finished
+type PendingWrite<'s, T> =
+    Pin<Box<dyn Future<Output = (&'s mut FastBoot<T>, Result<u64, FastBootError>)> + Send + 's>>;
+
+/// Data download helper
+///
+/// To success stream data over usb it needs to be sent in blocks that are multiple of the max
+/// endpoint size, otherwise the receiver may complain. It also should only send as much data as
+/// was indicate in the DATA command.
+///
+/// This helper ensures both invariants are met. To do this data needs to be sent by using
+/// [DataDownload::extend_from_slice], [DataDownload::get_mut_data] or the [AsyncWrite]
+/// implementation (e.g. using [tokio::io::copy]), after sending the data [DataDownload::finish]
+/// should be called to validate and finalize.
+///
+/// Dropping the helper before [DataDownload::finish] completed (e.g. by cancelling the future)
+/// cancels all queued transfers and leaves the device in the DATA phase; [FastBoot::resync] has
+/// to be used before sending further commands.
+pub struct DataDownload<'s, T: FastbootTransport> {
+    // Taken by the pending write, if any
+    fastboot: Option<&'s mut FastBoot<T>>,
+    writing: Option<PendingWrite<'s, T>>,
+    size: u64,
+    left: u64,
+    current: Vec<u8>,
+    queued: u64,
+    started: Instant,
+}
+
+impl<'s, T: FastbootTransport> DataDownload<'s, T> {
+    fn new(fastboot: &'s mut FastBoot<T>, size: u64) -> DataDownload<'s, T> {
+        let current = Self::allocate_buffer(&fastboot.transport);
+        Self {
+            fastboot: Some(fastboot),
+            writing: None,
+            size,
+            left: size,
+            current,
+            queued: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn start_write(&mut self, data: Vec<u8>) {
+        let fastboot = self.fastboot.take().expect("Write already in progress");
+        let len = data.len() as u64;
+        self.writing = Some(Box::pin(async move {
+            let timeout = fastboot.timeouts.data;
+            let written = with_timeout(timeout, fastboot.transport.write_data(data)).await;
+            let result = fastboot.check_timeout(written).map(|_| len);
+            (fastboot, result)
+        }));
+    }
+}
+
+impl<T: FastbootTransport> DataDownload<'_, T> {
+    /// Total size of the data transfer
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Data left to be sent/queued
+    pub fn left(&self) -> u64 {
+        self.left
+    }
+
+    /// Extend the streaming from a slice
+    ///
+    /// This will copy all provided data and send it out if enough is collected. The total amount
+    /// of data being sent should not exceed the download size
+    pub async fn extend_from_slice(&mut self, mut data: &[u8]) -> Result<(), DownloadError> {
+        self.write_done().await?;
+        self.update_size(data.len() as u64)?;
+        loop {
+            let left = self.current.capacity() - self.current.len();
+            if left >= data.len() {
+                self.current.extend_from_slice(data);
+                break;
+            } else {
+                self.current.extend_from_slice(&data[0..left]);
+                self.next_buffer().await?;
+                data = &data[left..];
+            }
+        }
+        Ok(())
+    }
+
+    /// This will provide a mutable reference to a [u8] of at most `max` size. The returned slice
+    /// should be completely filled with data to be downloaded to the device
+    ///
+    /// The total amount of data should not exceed the download size
+    pub async fn get_mut_data(&mut self, max: usize) -> Result<&mut [u8], DownloadError> {
+        self.write_done().await?;
+        if self.current.capacity() == self.current.len() {
+            self.next_buffer().await?;
+        }
+
+        let left = self.current.capacity() - self.current.len();
+        let size = left.min(max);
+        self.update_size(size as u64)?;
+
+        let len = self.current.len();
+        self.current.resize(len + size, 0);
+        Ok(&mut self.current[len..])
+    }
+
+    fn update_size(&mut self, size: u64) -> Result<(), DownloadError> {
+        if size > self.left {
+            return Err(DownloadError::IncorrectDataLength {
+                expected: self.size,
+                actual: size - self.left + self.size,
+            });
+        }
+        self.left -= size;
+        Ok(())
+    }
+
+    fn allocate_buffer(transport: &T) -> Vec<u8> {
+        // The transport ensures the buffer size matches its requirements (e.g. being a multiple
+        // of the maximum usb packet size)
+        Vec::with_capacity(transport.data_buffer_size())
+    }
+
+    fn fastboot(&mut self) -> &mut FastBoot<T> {
+        // Pending writes are always completed before the client is needed again
+        self.fastboot.as_deref_mut().expect("Write in progress")
+    }
+
+    fn start_next_buffer(&mut self) {
+        let mut next = Self::allocate_buffer(&self.fastboot().transport);
+        std::mem::swap(&mut next, &mut self.current);
+        self.start_write(next);
+    }
+
+    async fn next_buffer(&mut self) -> Result<(), DownloadError> {
+        self.start_next_buffer();
+        Ok(self.write_done().await?)
+    }
+
+    /// Wait for the pending write, if any, returning whether there was one
+    fn poll_write_complete(&mut self, cx: &mut Context<'_>) -> Poll<Result<bool, FastBootError>> {
+        let Some(writing) = &mut self.writing else {
+            return Poll::Ready(Ok(false));
+        };
+        let (fastboot, result) = ready!(writing.as_mut().poll(cx));
+        self.writing = None;
+        self.fastboot = Some(fastboot);
+        self.queued += result?;
+        Poll::Ready(Ok(true))
+    }
+
+    fn poll_write_done(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), FastBootError>> {
+        if ready!(self.poll_write_complete(cx))? {
+            self.report_transfer();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    async fn write_done(&mut self) -> Result<(), FastBootError> {
+        std::future::poll_fn(|cx| self.poll_write_done(cx)).await
+    }
+
+    /// Data actually sent to the device; Also keeps track of the data left to be sent in case
+    /// the download gets interrupted
+    fn acknowledged(&mut self) -> u64 {
+        let size = self.size;
+        let queued = self.queued;
+        let fastboot = self.fastboot();
+        let acknowledged = queued.saturating_sub(fastboot.transport.pending_data() as u64);
+        fastboot.data_left = size - acknowledged;
+        acknowledged
+    }
+
+    fn report_transfer(&mut self) {
+        let progress = TransferProgress {
+            size: self.size,
+            queued: self.queued,
+            acknowledged: self.acknowledged(),
+            elapsed: self.started.elapsed(),
+        };
+        self.fastboot()
+            .report_progress(Progress::Transfer(progress));
+    }
+
+    /// Finish all pending transfer
+    ///
+    /// This should only be called if all data has been queued up (matching the total size)
+    #[instrument(skip_all, err)]
+    pub async fn finish(mut self) -> Result<(), DownloadError> {
+        // Progress is reported once all data has been flushed
+        std::future::poll_fn(|cx| self.poll_write_complete(cx)).await?;
+        if self.left != 0 {
+            return Err(DownloadError::IncorrectDataLength {
+                expected: self.size,
+                actual: self.size - self.left,
+            });
+        }
+
+        if !self.current.is_empty() {
+            let current = std::mem::take(&mut self.current);
+            self.start_write(current);
+            std::future::poll_fn(|cx| self.poll_write_complete(cx)).await?;
+        }
+
+        let fastboot = self.fastboot();
+        let timeout = fastboot.timeouts.data;
+        let flushed = with_timeout(timeout, fastboot.transport.flush_data()).await;
+        fastboot.check_timeout(flushed)?;
+        self.report_transfer();
+
+        self.fastboot().handle_responses().await?;
+        Ok(())
+    }
+}
+
+/// Data is buffered until a full transfer buffer is collected; Flushing only waits for writes in
+/// progress, remaining data is sent by [DataDownload::finish].
+impl<T: FastbootTransport> AsyncWrite for DataDownload<'_, T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_done(cx)).map_err(io::Error::other)?;
+        if this.current.capacity() == this.current.len() {
+            this.start_next_buffer();
+            ready!(this.poll_write_done(cx)).map_err(io::Error::other)?;
+        }
+
+        let size = buf.len().min(this.current.capacity() - this.current.len());
+        this.update_size(size as u64).map_err(io::Error::other)?;
+        this.current.extend_from_slice(&buf[..size]);
+        Poll::Ready(Ok(size))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_done(cx).map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_write_done(cx).map_err(io::Error::other)
+    }
+}
+
+impl<T: FastbootTransport> Drop for DataDownload<'_, T> {
+    fn drop(&mut self) {
+        // Dropped (or failed) before the device acknowledged the data; Make sure queued data
+        // doesn't end up being sent later on. If a write is still in progress the client is
+        // unavailable, in which case the transfers get cancelled by a resync.
+        if self.fastboot.as_ref().is_some_and(|f| f.needs_resync) {
+            self.acknowledged();
+            self.fastboot().transport.cancel_transfers();
+        }
+    }
+}
+
+/// Error during data upload
+#[derive(Debug, Error)]
+pub enum UploadError {
+    #[error("Incorrect data length: expected {expected}, got {actual}")]
+    IncorrectDataLength { actual: u64, expected: u64 },
+    #[error(transparent)]
+    FastBoot(#[from] FastBootError),
+}
+
+/// Read of a block from the transport that is in progress; The future holds on to the client
+/// until the read finished
+type PendingRead<'s, T> = Pin<
+    Box<dyn Future<Output = (&'s mut FastBoot<T>, Result<Vec<u8>, FastBootError>)> + Send + 's>,
+>;
+
+/// Data upload helper
+///
+/// Receives the data the device sends after responding with DATA to an upload command. The data
+/// should be read using [DataUpload::read] until it returns `None` or using the [AsyncRead]
+/// implementation until end of file, after which [DataUpload::finish] should be called to
+/// retrieve the final status from the device.
+///
+/// Dropping the helper before [DataUpload::finish] completed leaves the device in the DATA
+/// phase; [FastBoot::resync] has to be used before sending further commands.
+pub struct DataUpload<'s, T: FastbootTransport> {
+    // Taken by the pending read, if any
+    fastboot: Option<&'s mut FastBoot<T>>,
+    reading: Option<PendingRead<'s, T>>,
+    size: u64,
+    left: u64,
+    // Received data not yet consumed by the AsyncRead implementation
+    current: Vec<u8>,
+    consumed: usize,
+}
+
+impl<'s, T: FastbootTransport> DataUpload<'s, T> {
+    fn new(fastboot: &'s mut FastBoot<T>, size: u64) -> DataUpload<'s, T> {
+        fastboot.upload_left = size;
+        Self {
+            fastboot: Some(fastboot),
+            reading: None,
+            size,
+            left: size,
+            current: vec![],
+            consumed: 0,
+        }
+    }
+
+    fn start_read(&mut self) {
+        let fastboot = self.fastboot.take().expect("Read already in progress");
+        let max = usize::try_from(self.left)
+            .unwrap_or(usize::MAX)
+            .min(fastboot.transport.data_buffer_size());
+        self.reading = Some(Box::pin(async move {
+            let timeout = fastboot.timeouts.data;
+            let data = with_timeout(timeout, fastboot.transport.read_data(max)).await;
+            let result = fastboot.check_timeout(data);
+            if let Ok(data) = &result {
+                // Keeps track of the data left to be received in case the upload gets interrupted
+                fastboot.upload_left = fastboot.upload_left.saturating_sub(data.len() as u64);
+            }
+            (fastboot, result)
+        }));
+    }
+}
+
+impl<T: FastbootTransport> DataUpload<'_, T> {
+    /// Total size of the data transfer
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Data left to be received
+    pub fn left(&self) -> u64 {
+        self.left
+    }
+
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Vec<u8>>, UploadError>> {
+        if self.reading.is_none() {
+            if self.left == 0 {
+                return Poll::Ready(Ok(None));
+            }
+            self.start_read();
+        }
+        let reading = self.reading.as_mut().expect("Read in progress");
+        let (fastboot, result) = ready!(reading.as_mut().poll(cx));
+        self.reading = None;
+        self.fastboot = Some(fastboot);
+
+        let data = result?;
+        if data.len() as u64 > self.left {
+            return Poll::Ready(Err(UploadError::IncorrectDataLength {
+                expected: self.size,
+                actual: (self.size - self.left).saturating_add(data.len() as u64),
+            }));
+        }
+        self.left -= data.len() as u64;
+        Poll::Ready(Ok(Some(data)))
+    }
+
+    /// Receive the next block of data, returns `None` once all data has been received
+    pub async fn read(&mut self) -> Result<Option<Vec<u8>>, UploadError> {
+        if self.consumed < self.current.len() {
+            let mut current = std::mem::take(&mut self.current);
+            current.drain(..self.consumed);
+            self.consumed = 0;
+            return Ok(Some(current));
+        }
+        std::future::poll_fn(|cx| self.poll_next(cx)).await
+    }
+
+    /// Receive all remaining data and finish the transfer
+    pub async fn read_to_end(mut self) -> Result<Vec<u8>, UploadError> {
+        let mut out = Vec::with_capacity(self.left.try_into().unwrap_or(0));
+        while let Some(data) = self.read().await? {
+            out.extend_from_slice(&data);
+        }
+        self.finish().await?;
+        Ok(out)
+    }
+
+    /// Finish the transfer
+    ///
+    /// This should only be called once all data has been received
+    #[instrument(skip_all, err)]
+    pub async fn finish(mut self) -> Result<(), UploadError> {
+        if self.left != 0 {
+            return Err(UploadError::IncorrectDataLength {
+                expected: self.size,
+                actual: self.size - self.left,
+            });
+        }
+        let fastboot = self.fastboot.take().expect("Read in progress");
+        fastboot.handle_responses().await?;
+        Ok(())
+    }
+}
+
+impl<T: FastbootTransport> Drop for DataUpload<'_, T> {
+    fn drop(&mut self) {
+        // Dropped before the final response was received; The remaining data is discarded by a
+        // resync
+        if let Some(fastboot) = self.fastboot.as_deref_mut() {
+            if fastboot.needs_resync {
+                fastboot.transport.cancel_transfers();
+            }
+        }
+    }
+}
+
+/// Blocks are received from the transport as they are requested; End of file is signalled once
+/// all data has been received, after which [DataUpload::finish] should still be called.
+impl<T: FastbootTransport> AsyncRead for DataUpload<'_, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.consumed == this.current.len() {
+            match ready!(this.poll_next(cx)).map_err(io::Error::other)? {
+                Some(data) => {
+                    this.current = data;
+                    this.consumed = 0;
+                }
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+        let available = &this.current[this.consumed..];
+        let size = available.len().min(buf.remaining());
+        buf.put_slice(&available[..size]);
+        this.consumed += size;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockTransport;
+    use android_sparse_image::{split::split_image, ChunkHeader, FileHeader};
+    use futures::executor::block_on;
+
+    fn okay(value: &str) -> FastBootResponse {
+        FastBootResponse::Okay(value.to_string())
+    }
+
+    fn info(value: &str) -> FastBootResponse {
+        FastBootResponse::Info(value.to_string())
+    }
+
+    fn text(value: &str) -> FastBootResponse {
+        FastBootResponse::Text(value.to_string())
+    }
+
+    #[test]
+    fn get_var() {
+        let mut mock = MockTransport::new();
+        mock.expect("getvar:version", [info("ignored"), okay("0.4")]);
+        let mut fb = FastBoot::new(mock);
+
+        assert_eq!(block_on(fb.get_var("version")).unwrap(), "0.4");
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn command_failure() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "erase:userdata",
+            [FastBootResponse::Fail("not allowed".to_string())],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        let err = block_on(fb.erase("userdata")).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(f) if f == "not allowed"));
+    }
+
+    #[test]
+    fn get_all_vars() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "getvar:all",
+            [
+                info("version: 0.4"),
+                info("max-download-size: 0x10000"),
+                FastBootResponse::Text("some text".to_string()),
+                info("partition-size:boot_a: 0x4000"),
+                info("partition-size:system_a:0x8000"),
+                info("version-bootloader:u-boot:2024.01"),
+                okay(""),
+            ],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        let vars = block_on(fb.get_all_vars()).unwrap();
+        assert_eq!(vars.len(), 5);
+        assert_eq!(vars["partition-size:system_a"], "0x8000");
+        assert_eq!(vars["version-bootloader"], "u-boot:2024.01");
+        assert_eq!(vars["version"], "0.4");
+        assert_eq!(vars["max-download-size"], "0x10000");
+        assert_eq!(vars["partition-size:boot_a"], "0x4000");
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let mut mock = MockTransport::new();
+        mock.expect_no_response("getvar:version")
+            .expect("getvar:version", [okay("0.4")])
+            .expect("getvar:product", [okay("virtual")]);
+        let mut fb = FastBoot::new(mock);
+        let timeouts = Timeouts {
+            command: Some(Duration::from_millis(50)),
+            long_command: None,
+            data: None,
+        };
+        fb.set_timeouts(timeouts);
+        assert_eq!(
+            timeouts.for_command(&FastBootCommand::Erase("userdata")),
+            None
+        );
+
+        let err = fb.get_var("version").await.unwrap_err();
+        assert!(matches!(err, FastBootError::Timeout(t) if t == Duration::from_millis(50)));
+        assert_eq!(fb.transport().cancelled(), 1);
+        assert!(fb.needs_resync());
+
+        fb.resync().await.unwrap();
+        assert_eq!(fb.get_var("product").await.unwrap(), "virtual");
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn message_handler() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "erase:userdata",
+            [info("erasing"), text("50%"), info("done"), okay("")],
+        )
+        .expect(
+            "download:00000004",
+            [info("receiving"), FastBootResponse::Data(4)],
+        )
+        .expect_data(4, [text("received"), okay("")])
+        .expect("getvar:all", [info("version: 0.4"), text("vars"), okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        let messages = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let m = messages.clone();
+        fb.set_message_handler(move |message| m.lock().unwrap().push(message.clone()));
+
+        block_on(fb.erase("userdata")).unwrap();
+        block_on(async {
+            let mut download = fb.download(4).await.unwrap();
+            download.extend_from_slice(&[0, 1, 2, 3]).await.unwrap();
+            download.finish().await.unwrap();
+        });
+        block_on(fb.get_all_vars()).unwrap();
+
+        assert_eq!(
+            *messages.lock().unwrap(),
+            [
+                DeviceMessage::Info("erasing".to_string()),
+                DeviceMessage::Text("50%".to_string()),
+                DeviceMessage::Info("done".to_string()),
+                DeviceMessage::Info("receiving".to_string()),
+                DeviceMessage::Text("received".to_string()),
+                DeviceMessage::Text("vars".to_string()),
+            ]
+        );
+
+        fb.clear_message_handler();
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn download_and_flash() {
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(512);
+        mock.expect("download:00002710", [FastBootResponse::Data(10000)])
+            .expect_data(10000, [okay("")])
+            .expect("flash:boot", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            let mut download = fb.download(data.len() as u64).await.unwrap();
+            download.extend_from_slice(&data[..100]).await.unwrap();
+            let mut left = data.len() - 100;
+            while left > 0 {
+                let offset = data.len() - left;
+                let buf = download.get_mut_data(left).await.unwrap();
+                buf.copy_from_slice(&data[offset..offset + buf.len()]);
+                left -= buf.len();
+            }
+            assert_eq!(download.left(), 0);
+            download.finish().await.unwrap();
+            fb.flash("boot").await.unwrap();
+        });
+
+        let mock = fb.into_transport();
+        assert!(mock.is_done());
+        assert_eq!(mock.downloads(), std::slice::from_ref(&data));
+        let mut written = b"download:00002710".to_vec();
+        written.extend_from_slice(&data);
+        written.extend_from_slice(b"flash:boot");
+        assert_eq!(mock.written(), written);
+    }
+
+    #[tokio::test]
+    async fn download_64bit() {
+        let mut mock = MockTransport::new();
+        mock.expect("getvar:max-download-size", [okay("0x0000000200000000")])
+            .expect(
+                "download:0000000100000000",
+                [FastBootResponse::Fail("not enough space".to_string())],
+            )
+            .expect("getvar:max-download-size", [okay("0x0000000200000000")])
+            .expect(
+                "getvar:max-download-size",
+                [FastBootResponse::Fail("unknown variable".to_string())],
+            );
+        let mut fb = FastBoot::new(mock);
+
+        // The size is sent to the device rather than rejected up front
+        let err = fb.download(0x1_0000_0000).await.err().unwrap();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+
+        let err = fb.download(0x2_0000_0001).await.err().unwrap();
+        assert!(matches!(
+            err,
+            FastBootError::DownloadTooLarge {
+                size: 0x2_0000_0001,
+                max: 0x2_0000_0000
+            }
+        ));
+        let err = fb.download(0x1_0000_0000).await.err().unwrap();
+        assert!(matches!(
+            err,
+            FastBootError::DownloadTooLarge {
+                max: 0xffff_ffff,
+                ..
+            }
+        ));
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn progress() {
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(4);
+        mock.set_queue_depth(1);
+        mock.expect("download:0000000a", [FastBootResponse::Data(10)])
+            .expect_data(10, [okay("")])
+            .expect("flash:boot", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        let events = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let e = events.clone();
+        fb.set_progress_handler(move |progress| e.lock().unwrap().push(progress.clone()));
+        let part = Some(Part { index: 1, count: 2 });
+        fb.set_part(part);
+
+        block_on(async {
+            let mut download = fb.download(10).await.unwrap();
+            download.extend_from_slice(&[0; 10]).await.unwrap();
+            download.finish().await.unwrap();
+            fb.flash("boot").await.unwrap();
+        });
+
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], Progress::Downloading { size: 10, part });
+        let transfers: Vec<_> = events[1..events.len() - 1]
+            .iter()
+            .map(|e| match e {
+                Progress::Transfer(t) => (t.size, t.queued, t.acknowledged),
+                e => panic!("Unexpected event: {e:?}"),
+            })
+            .collect();
+        // Data is acknowledged once its transfer completes; The final event follows the flush
+        assert_eq!(transfers, [(10, 4, 0), (10, 8, 4), (10, 10, 10)]);
+        assert_eq!(
+            events[events.len() - 1],
+            Progress::Flashing {
+                partition: "boot".to_string(),
+                part
+            }
+        );
+
+        let progress = TransferProgress {
+            size: 3000,
+            queued: 2000,
+            acknowledged: 1000,
+            elapsed: Duration::from_secs(2),
+        };
+        assert_eq!(progress.throughput(), 500.0);
+        assert_eq!(progress.eta(), Some(Duration::from_secs(4)));
+    }
+
+    #[tokio::test]
+    async fn download_dropped() {
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(4);
+        mock.expect("download:00000010", [FastBootResponse::Data(16)])
+            .expect_data(16, [okay("")])
+            .expect("getvar:version", [okay("0.4")])
+            .expect("getvar:product", [okay("virtual")]);
+        let mut fb = FastBoot::new(mock);
+        fb.set_timeouts(Timeouts {
+            command: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+
+        // Abandon the download halfway through
+        {
+            let mut download = fb.download(16).await.unwrap();
+            download.extend_from_slice(&[1; 8]).await.unwrap();
+        }
+        assert!(fb.needs_resync());
+        assert_eq!(fb.transport().cancelled(), 1);
+        let err = fb.get_var("product").await.unwrap_err();
+        assert!(matches!(err, FastBootError::ResyncRequired));
+
+        // The remainder of the download is padded, after which the device accepts commands
+        fb.resync().await.unwrap();
+        assert!(!fb.needs_resync());
+        assert_eq!(fb.get_var("product").await.unwrap(), "virtual");
+
+        let mock = fb.into_transport();
+        assert!(mock.is_done());
+        let mut expected = vec![1; 4];
+        expected.resize(16, 0);
+        assert_eq!(mock.downloads(), [expected]);
+    }
+
+    #[tokio::test]
+    async fn command_cancelled() {
+        let mut mock = MockTransport::new();
+        // The device goes into the DATA phase, but the DATA response is never received
+        mock.expect("download:00000010", [])
+            .expect_data(16, [okay("")]);
+        let mut fb = FastBoot::new(mock);
+        fb.set_timeouts(Timeouts {
+            command: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+
+        // e.g. a select! around the download
+        let cancelled = tokio::time::timeout(Duration::from_millis(10), fb.download(16))
+            .await
+            .is_err();
+        assert!(cancelled);
+        assert!(fb.needs_resync());
+        let err = fb.get_var("product").await.unwrap_err();
+        assert!(matches!(err, FastBootError::ResyncRequired));
+
+        // The client doesn't know the device is waiting for data, so the probe is taken as data
+        let err = fb.resync().await.unwrap_err();
+        assert!(matches!(err, FastBootError::ReconnectRequired));
+        assert!(fb.needs_resync());
+    }
+
+    #[tokio::test]
+    async fn late_data_response() {
+        let mut mock = MockTransport::new();
+        mock.expect("download:00000010", [FastBootResponse::Data(16)])
+            .expect_data(16, [okay("")])
+            .expect("getvar:version", [okay("0.4")])
+            .expect_upload([1; 8], [okay("")])
+            .expect("getvar:version", [okay("0.4")]);
+        let mut fb = FastBoot::new(mock);
+        fb.set_timeouts(Timeouts {
+            command: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+
+        // As if the command futures were cancelled before the DATA responses were received
+        fb.send_command(FastBootCommand::<&str>::Download(16))
+            .await
+            .unwrap();
+        fb.resync().await.unwrap();
+        fb.send_command(FastBootCommand::<&str>::Upload)
+            .await
+            .unwrap();
+        fb.resync().await.unwrap();
+
+        let mock = fb.into_transport();
+        assert!(mock.is_done());
+        assert_eq!(mock.downloads(), [vec![0; 16]]);
+    }
+
+    #[tokio::test]
+    async fn upload_dropped() {
+        let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(512);
+        mock.expect_upload(&data, [okay("")])
+            .expect("getvar:version", [okay("0.4")]);
+        let mut fb = FastBoot::new(mock);
+        fb.set_timeouts(Timeouts {
+            command: Some(Duration::from_millis(50)),
+            ..Default::default()
+        });
+
+        {
+            let mut upload = fb.upload().await.unwrap();
+            upload.read().await.unwrap().unwrap();
+        }
+        assert!(fb.needs_resync());
+        assert_eq!(fb.transport().cancelled(), 1);
+
+        // The rest of the upload and the final response are discarded
+        fb.resync().await.unwrap();
+        assert!(!fb.needs_resync());
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn download_too_much() {
+        let mut mock = MockTransport::new();
+        mock.expect("download:00000010", [FastBootResponse::Data(16)]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            let mut download = fb.download(16).await.unwrap();
+            let err = download.extend_from_slice(&[0; 17]).await.unwrap_err();
+            assert!(matches!(
+                err,
+                DownloadError::IncorrectDataLength {
+                    actual: 17,
+                    expected: 16
+                }
+            ));
+        });
+    }
+
+    #[test]
+    fn download_from_reader() {
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(1024);
+        mock.expect("download:00000bb8", [FastBootResponse::Data(3000)])
+            .expect_data(3000, [okay("")])
+            .expect("download:00000bb8", [FastBootResponse::Data(3000)])
+            .expect_data(3000, [okay("")])
+            .expect("download:00000bb9", [FastBootResponse::Data(3001)])
+            .expect_data(3001, []);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            fb.download_from_reader(3000, &data[..]).await.unwrap();
+            fb.download_from_blocking_reader(3000, std::io::Cursor::new(&data))
+                .await
+                .unwrap();
+            let err = fb.download_from_reader(3001, &data[..]).await.unwrap_err();
+            assert!(
+                matches!(err, DownloadError::Read(e) if e.kind() == io::ErrorKind::UnexpectedEof)
+            );
+        });
+
+        assert!(fb.needs_resync());
+        let mock = fb.into_transport();
+        // The device is still waiting for the rest of the last download
+        assert!(!mock.is_done());
+        assert_eq!(mock.downloads(), [data.clone(), data]);
+    }
+
+    #[test]
+    fn download_async_write() {
+        let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(512);
+        mock.expect("download:00002710", [FastBootResponse::Data(10000)])
+            .expect_data(10000, [okay("")])
+            .expect("download:00000010", [FastBootResponse::Data(16)]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            let mut download = fb.download(10000).await.unwrap();
+            let copied = tokio::io::copy(&mut &data[..], &mut download)
+                .await
+                .unwrap();
+            assert_eq!(copied, 10000);
+            assert_eq!(download.left(), 0);
+            download.finish().await.unwrap();
+
+            let mut download = fb.download(16).await.unwrap();
+            let err = tokio::io::copy(&mut &data[..17], &mut download)
+                .await
+                .unwrap_err();
+            let err = err
+                .into_inner()
+                .unwrap()
+                .downcast::<DownloadError>()
+                .unwrap();
+            assert!(matches!(
+                *err,
+                DownloadError::IncorrectDataLength {
+                    actual: 17,
+                    expected: 16
+                }
+            ));
+        });
+
+        let mock = fb.into_transport();
+        assert!(mock.is_done());
+        assert_eq!(mock.downloads(), std::slice::from_ref(&data));
+    }
+
+    #[test]
+    fn stage_and_oem() {
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(1024);
+        mock.expect("download:00000bb8", [FastBootResponse::Data(3000)])
+            .expect_data(3000, [okay("")])
+            .expect("oem provision", [info("provisioning"), okay("done")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            fb.stage(data.len() as u64, &data[..]).await.unwrap();
+            assert_eq!(fb.oem("provision").await.unwrap().value, "done");
+        });
+
+        let mock = fb.into_transport();
+        assert!(mock.is_done());
+        assert_eq!(mock.downloads(), std::slice::from_ref(&data));
+    }
+
+    #[test]
+    fn stage_short_read() {
+        let mut mock = MockTransport::new();
+        mock.expect("download:00000010", [FastBootResponse::Data(16)]);
+        let mut fb = FastBoot::new(mock);
+
+        let err = block_on(fb.stage(16, &[0u8; 8][..])).unwrap_err();
+        assert!(
+            matches!(err, DownloadError::Read(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+    }
+
+    #[test]
+    fn oem_messages() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "oem device-info",
+            [
+                info("Device tampered: false"),
+                FastBootResponse::Text("unlocked".to_string()),
+                info("Device unlocked: false"),
+                okay(""),
+            ],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        let output = block_on(fb.oem("device-info")).unwrap();
+        assert_eq!(output.value, "");
+        assert_eq!(
+            output.messages,
+            [
+                DeviceMessage::Info("Device tampered: false".to_string()),
+                DeviceMessage::Text("unlocked".to_string()),
+                DeviceMessage::Info("Device unlocked: false".to_string()),
+            ]
+        );
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn raw_command() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "flashing get_unlock_ability",
+            [info("get_unlock_ability: 1"), okay("")],
+        )
+        .expect(
+            "bogus",
+            [FastBootResponse::Fail("unknown command".to_string())],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        let output = block_on(fb.raw_command("flashing get_unlock_ability")).unwrap();
+        assert_eq!(
+            output.messages,
+            [DeviceMessage::Info("get_unlock_ability: 1".to_string())]
+        );
+        let err = block_on(fb.raw_command("bogus")).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(f) if f == "unknown command"));
+    }
+
+    #[test]
+    fn boot_image() {
+        let image = vec![0x42; 2048];
+        let mut mock = MockTransport::new();
+        mock.expect("download:00000800", [FastBootResponse::Data(2048)])
+            .expect_data(2048, [okay("")])
+            .expect("boot", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(fb.boot_image(image.len() as u64, &image[..])).unwrap();
+        let mock = fb.into_transport();
+        assert!(mock.is_done());
+        assert_eq!(mock.downloads(), std::slice::from_ref(&image));
+    }
+
+    #[test]
+    fn boot_disconnect() {
+        let mut mock = MockTransport::new();
+        // Device goes away without responding
+        mock.expect("boot", []);
+        let mut fb = FastBoot::new(mock);
+        block_on(fb.boot()).unwrap();
+
+        let mut mock = MockTransport::new();
+        mock.expect("boot", [FastBootResponse::Fail("no image".to_string())]);
+        let mut fb = FastBoot::new(mock);
+        let err = block_on(fb.boot()).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(f) if f == "no image"));
+    }
+
+    #[test]
+    fn continue_and_powerdown() {
+        let mut mock = MockTransport::new();
+        mock.expect("continue", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+        block_on(fb.continue_boot()).unwrap();
+        assert!(fb.transport().is_done());
+
+        let mut mock = MockTransport::new();
+        mock.expect("powerdown", []);
+        let mut fb = FastBoot::new(mock);
+        block_on(fb.powerdown()).unwrap();
+    }
+
+    #[test]
+    fn reboot_targets() {
+        let targets = [
+            (RebootTarget::System, "reboot"),
+            (RebootTarget::Bootloader, "reboot-bootloader"),
+            (RebootTarget::Recovery, "reboot-recovery"),
+            (RebootTarget::Fastboot, "reboot-fastboot"),
+            (RebootTarget::Other("edl".to_string()), "reboot:edl"),
+        ];
+        for (target, cmd) in targets {
+            let mut mock = MockTransport::new();
+            mock.expect(cmd, [okay("")]);
+            let mut fb = FastBoot::new(mock);
+            block_on(fb.reboot_to(&target)).unwrap();
+            assert!(fb.transport().is_done());
+        }
+
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "reboot-recovery",
+            [FastBootResponse::Fail("not supported".to_string())],
+        );
+        let mut fb = FastBoot::new(mock);
+        let err = block_on(fb.reboot_to(&RebootTarget::Recovery)).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+    }
+
+    #[test]
+    fn sizes() {
+        let mut mock = MockTransport::new();
+        mock.expect("getvar:max-download-size", [okay("268435456")])
+            .expect("getvar:partition-size:super", [okay("0x0000000200000000")])
+            .expect("getvar:max-download-size", [okay("bogus")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            assert_eq!(fb.max_download_size().await.unwrap(), 256 * 1024 * 1024);
+            assert_eq!(fb.partition_size("super").await.unwrap(), 8 << 30);
+            let err = fb.max_download_size().await.unwrap_err();
+            assert!(matches!(err, FastBootError::FastbootUnexpectedReply));
+        });
+    }
+
+    #[test]
+    fn slot_parse() {
+        assert_eq!("a".parse::<Slot>().unwrap(), Slot::A);
+        assert_eq!("_b".parse::<Slot>().unwrap(), Slot::B);
+        assert!("c".parse::<Slot>().is_err());
+        assert_eq!(Slot::A.other(), Slot::B);
+        assert_eq!(Slot::B.suffix(), "_b");
+        assert_eq!(Slot::B.to_string(), "b");
+    }
+
+    #[test]
+    fn slot_info() {
+        let mut mock = MockTransport::new();
+        mock.expect("getvar:slot-count", [okay("2")])
+            .expect("getvar:current-slot", [okay("_b")])
+            .expect("getvar:slot-successful:a", [okay("yes")])
+            .expect("getvar:slot-unbootable:a", [okay("no")])
+            .expect("set_active:a", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            assert_eq!(fb.slot_count().await.unwrap(), 2);
+            assert_eq!(fb.current_slot().await.unwrap(), Slot::B);
+            assert!(fb.slot_successful(Slot::A).await.unwrap());
+            assert!(!fb.slot_unbootable(Slot::A).await.unwrap());
+            fb.set_active(Slot::A).await.unwrap();
+        });
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn flash_slot() {
+        let mut mock = MockTransport::new();
+        mock.expect("getvar:has-slot:boot", [okay("yes")])
+            .expect("getvar:current-slot", [okay("a")])
+            .expect("flash:boot_a", [okay("")])
+            .expect("getvar:has-slot:boot", [okay("yes")])
+            .expect("getvar:current-slot", [okay("a")])
+            .expect("flash:boot_b", [okay("")])
+            .expect("getvar:has-slot:boot", [okay("yes")])
+            .expect("flash:boot_a", [okay("")])
+            .expect("flash:boot_b", [okay("")])
+            .expect("getvar:has-slot:userdata", [okay("no")])
+            .expect("flash:userdata", [okay("")])
+            .expect(
+                "getvar:has-slot:misc",
+                [FastBootResponse::Fail("unknown partition".to_string())],
+            )
+            .expect("flash:misc", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            fb.flash_slot("boot", SlotSelection::Current).await.unwrap();
+            fb.flash_slot("boot", SlotSelection::Other).await.unwrap();
+            fb.flash_slot("boot", SlotSelection::All).await.unwrap();
+            fb.flash_slot("userdata", SlotSelection::Slot(Slot::B))
+                .await
+                .unwrap();
+            fb.flash_slot("misc", SlotSelection::Current).await.unwrap();
+        });
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn lock_state() {
+        let mut mock = MockTransport::new();
+        mock.expect("getvar:unlocked", [okay("no")])
+            .expect("getvar:secure", [okay("yes")])
+            .expect("getvar:unlocked", [okay("yes")])
+            .expect(
+                "getvar:secure",
+                [FastBootResponse::Fail("unknown variable".to_string())],
+            );
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            let state = fb.lock_state().await.unwrap();
+            assert_eq!(
+                state,
+                LockState {
+                    unlocked: false,
+                    secure: Some(true)
+                }
+            );
+            let state = fb.lock_state().await.unwrap();
+            assert_eq!(
+                state,
+                LockState {
+                    unlocked: true,
+                    secure: None
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn flashing_lock_unlock() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "flashing get_unlock_ability",
+            [info("get_unlock_ability: 1"), okay("")],
+        )
+        .expect("flashing unlock", [okay("")])
+        .expect("flashing unlock_critical", [okay("")])
+        .expect("flashing lock_critical", [okay("")])
+        .expect("flashing lock", [okay("")])
+        .expect(
+            "flashing get_unlock_ability",
+            [okay("get_unlock_ability: 0")],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            assert!(fb.get_unlock_ability().await.unwrap());
+            fb.flashing_unlock().await.unwrap();
+            fb.flashing_unlock_critical().await.unwrap();
+            fb.flashing_lock_critical().await.unwrap();
+            fb.flashing_lock().await.unwrap();
+            assert!(!fb.get_unlock_ability().await.unwrap());
+        });
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn device_locked_error() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "flash:boot",
+            [FastBootResponse::Fail(
+                "Flashing is not allowed in Lock State".to_string(),
+            )],
+        )
+        .expect(
+            "flashing lock",
+            [FastBootResponse::Fail("Device already locked".to_string())],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        let err = block_on(fb.flash("boot")).unwrap_err();
+        assert!(matches!(err, FastBootError::DeviceLocked(_)));
+        let err = block_on(fb.flashing_lock()).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+    }
+
+    #[test]
+    fn device_unlocked_failures() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "flash:boot",
+            [FastBootResponse::Fail(
+                "partition not flashable in unlocked state".to_string(),
+            )],
+        )
+        .expect(
+            "erase:userdata",
+            [FastBootResponse::Fail(
+                "Not allowed on an un-locked device".to_string(),
+            )],
+        )
+        .expect(
+            "flashing unlock",
+            [FastBootResponse::Fail(
+                "Unlock state already set".to_string(),
+            )],
+        )
+        .expect(
+            "getvar:frp",
+            [FastBootResponse::Fail("Device is locked".to_string())],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        let err = block_on(fb.flash("boot")).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+        let err = block_on(fb.erase("userdata")).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+        let err = block_on(fb.flashing_unlock()).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+        // Only flash, erase and flashing commands are classified
+        let err = block_on(fb.get_var("frp")).unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+    }
+
+    #[test]
+    fn logical_partitions() {
+        let mut mock = MockTransport::new();
+        mock.expect("create-logical-partition:product_a:4096", [okay("")])
+            .expect("delete-logical-partition:product_b", [okay("")])
+            .expect("update-super:super:wipe", [okay("")])
+            .expect("getvar:is-logical:system_a", [okay("yes")])
+            .expect("resize-logical-partition:system_a:1048576", [okay("")])
+            .expect("getvar:is-logical:boot_a", [okay("no")])
+            .expect(
+                "getvar:is-logical:vbmeta",
+                [FastBootResponse::Fail("unknown variable".to_string())],
+            );
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            fb.create_logical_partition("product_a", 4096)
+                .await
+                .unwrap();
+            fb.delete_logical_partition("product_b").await.unwrap();
+            fb.update_super("super", true).await.unwrap();
+            assert!(fb
+                .fit_logical_partition("system_a", 1024 * 1024)
+                .await
+                .unwrap());
+            assert!(!fb.fit_logical_partition("boot_a", 4096).await.unwrap());
+            assert!(!fb.is_logical("vbmeta").await.unwrap());
+        });
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn snapshot_update() {
+        let mut mock = MockTransport::new();
+        mock.expect("getvar:snapshot-update-status", [okay("merging")])
+            .expect(
+                "flash:system_a",
+                [FastBootResponse::Fail(
+                    "Cannot flash system_a while a snapshot update is in progress".to_string(),
+                )],
+            )
+            .expect("snapshot-update:cancel", [okay("")])
+            .expect("getvar:snapshot-update-status", [okay("none")])
+            .expect("snapshot-update:merge", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            assert_eq!(
+                fb.snapshot_update_status().await.unwrap(),
+                SnapshotUpdateStatus::Merging
+            );
+            let err = fb.flash("system_a").await.unwrap_err();
+            assert!(matches!(err, FastBootError::SnapshotUpdateInProgress(_)));
+            fb.snapshot_update_cancel().await.unwrap();
+            assert_eq!(
+                fb.snapshot_update_status().await.unwrap(),
+                SnapshotUpdateStatus::None
+            );
+            fb.snapshot_update_merge().await.unwrap();
+        });
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn gsi() {
+        let mut mock = MockTransport::new();
+        mock.expect("gsi:status", [info("Not running"), okay("")])
+            .expect("gsi:disable", [okay("")])
+            .expect("gsi:wipe", [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            let status = fb.gsi_status().await.unwrap();
+            assert_eq!(
+                status.messages,
+                [DeviceMessage::Info("Not running".to_string())]
+            );
+            fb.gsi_disable().await.unwrap();
+            fb.gsi_wipe().await.unwrap();
+        });
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn upload() {
+        let data: Vec<u8> = (0..10000).map(|i| (i / 7) as u8).collect();
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(512);
+        mock.expect_upload(&data, [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        let uploaded = block_on(async {
+            let mut upload = fb.upload().await.unwrap();
+            assert_eq!(upload.size(), 10000);
+            let first = upload.read().await.unwrap().unwrap();
+            assert_eq!(first.len(), 512);
+            assert_eq!(upload.left(), 10000 - 512);
+            let mut rest = upload.read_to_end().await.unwrap();
+            rest.splice(0..0, first);
+            rest
+        });
+        assert_eq!(uploaded, data);
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn upload_async_read() {
+        let data: Vec<u8> = (0..10000).map(|i| (i / 3) as u8).collect();
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(512);
+        mock.expect_upload(&data, [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        let uploaded = block_on(async {
+            let mut upload = fb.upload().await.unwrap();
+            // Smaller than the blocks received from the transport
+            let mut start = [0; 100];
+            upload.read_exact(&mut start).await.unwrap();
+            let mut uploaded = start.to_vec();
+            // The remainder of the partially consumed block is returned first
+            let block = upload.read().await.unwrap().unwrap();
+            assert_eq!(block.len(), 412);
+            uploaded.extend_from_slice(&block);
+            tokio::io::copy(&mut upload, &mut uploaded).await.unwrap();
+            assert_eq!(upload.left(), 0);
+            upload.finish().await.unwrap();
+            uploaded
+        });
+        assert_eq!(uploaded, data);
+        assert!(fb.transport().is_done());
+    }
+
+    #[test]
+    fn upload_nothing_staged() {
+        let mut mock = MockTransport::new();
+        mock.expect(
+            "upload",
+            [FastBootResponse::Fail("No data staged".to_string())],
+        );
+        let mut fb = FastBoot::new(mock);
+
+        let err = block_on(fb.upload()).err().unwrap();
+        assert!(matches!(err, FastBootError::FastbootFailed(f) if f == "No data staged"));
+    }
+
+    #[test]
+    fn upload_finish_early() {
+        let mut mock = MockTransport::new();
+        mock.expect_upload([0; 16], [okay("")]);
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            let upload = fb.upload().await.unwrap();
+            let err = upload.finish().await.unwrap_err();
+            assert!(matches!(
+                err,
+                UploadError::IncorrectDataLength {
+                    actual: 0,
+                    expected: 16
+                }
+            ));
+        });
+    }
+
+    #[test]
+    fn flash_sparse_multipart() {
+        const BLOCK_SIZE: u32 = 4096;
+        let header = FileHeader {
+            block_size: BLOCK_SIZE,
+            blocks: 8 + 64,
+            chunks: 2,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_fill(8),
+            ChunkHeader::new_raw(64, BLOCK_SIZE),
+        ];
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(&chunks[0].to_bytes());
+        image.extend_from_slice(&[0xaa; 4]);
+        image.extend_from_slice(&chunks[1].to_bytes());
+        image.extend((0..64 * BLOCK_SIZE).map(|i| (i / BLOCK_SIZE) as u8));
+
+        let splits = split_image(&header, &chunks, 64 * 1024).unwrap();
+        assert!(splits.len() > 1);
+
+        let mut mock = MockTransport::new();
+        mock.set_data_buffer_size(16 * 1024);
+        let mut expected = vec![];
+        for split in &splits {
+            let size = split.sparse_size();
+            mock.expect(
+                format!("download:{size:08x}"),
+                [FastBootResponse::Data(size as u64)],
+            )
+            .expect_data(size, [okay("")])
+            .expect("flash:system", [info("flashing"), okay("")]);
+
+            let mut part = split.header.to_bytes().to_vec();
+            for chunk in &split.chunks {
+                part.extend_from_slice(&chunk.header.to_bytes());
+                part.extend_from_slice(&image[chunk.offset..chunk.offset + chunk.size]);
+            }
+            expected.push(part);
+        }
+        let mut fb = FastBoot::new(mock);
+
+        block_on(async {
+            for split in &splits {
+                let mut download = fb.download(split.sparse_size() as u64).await.unwrap();
+                download
+                    .extend_from_slice(&split.header.to_bytes())
+                    .await
+                    .unwrap();
+                for chunk in &split.chunks {
+                    download
+                        .extend_from_slice(&chunk.header.to_bytes())
+                        .await
+                        .unwrap();
+                    download
+                        .extend_from_slice(&image[chunk.offset..chunk.offset + chunk.size])
+                        .await
+                        .unwrap();
+                }
+                download.finish().await.unwrap();
+                fb.flash("system").await.unwrap();
+            }
+        });
+
+        let mock = fb.into_transport();
+        assert!(mock.is_done());
+        assert_eq!(mock.downloads(), expected);
+    }
+}
@@ -1,6 +1,28 @@
-#![doc = include_str!("../README.md")]
+// The README example uses the nusb transport
+#![cfg_attr(feature = "nusb", doc = include_str!("../README.md"))]
+#![cfg_attr(not(feature = "nusb"), doc = "Fastboot protocol implementation")]
 
+/// Generic fastboot client implementation
+pub mod client;
+/// Scripted mock transport for testing
+pub mod mock;
 /// Nusb based fastboot client implementation
+#[cfg(feature = "nusb")]
 pub mod nusb;
 /// Lowlevel protocol types and helpers
 pub mod protocol;
+/// Device side fastboot server implementation
+pub mod server;
+/// Fastboot over TCP client implementation
+#[cfg(feature = "tcp")]
+pub mod tcp;
+/// Transport abstraction for the fastboot client
+pub mod transport;
+/// Fastboot over UDP client implementation
+#[cfg(feature = "udp")]
+pub mod udp;
+/// Typed device variables
+pub mod variables;
+/// Virtual fastboot device backed by partition files
+#[cfg(feature = "virtual-device")]
+pub mod virtual_device;
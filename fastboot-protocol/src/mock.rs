@@ -0,0 +1,299 @@
+use std::{collections::VecDeque, io};
+
+use crate::protocol::FastBootResponse;
+use crate::transport::{FastbootTransport, DEFAULT_DATA_BUFFER_SIZE};
+
+enum Step {
+    Command {
+        command: Vec<u8>,
+        responses: Vec<Vec<u8>>,
+        hang: bool,
+    },
+    Data {
+        size: usize,
+        responses: Vec<Vec<u8>>,
+    },
+}
+
+struct PendingData {
+    size: usize,
+    data: Vec<u8>,
+    responses: Vec<Vec<u8>>,
+}
+
+/// Scripted mock transport
+///
+/// Intended for testing fastboot flows without a device. The test scripts the commands the client
+/// is expected to send (and the data it is expected to download) together with the responses of
+/// the device. All bytes written by the client are recorded for later inspection.
+///
+/// ```
+/// # use fastboot_protocol::{client::FastBoot, mock::MockTransport, protocol::FastBootResponse};
+/// # futures::executor::block_on(async {
+/// let mut mock = MockTransport::new();
+/// mock.expect("getvar:version", [FastBootResponse::Okay("0.4".to_string())]);
+///
+/// let mut fb = FastBoot::new(mock);
+/// assert_eq!(fb.get_var("version").await.unwrap(), "0.4");
+/// assert!(fb.transport().is_done());
+/// # });
+/// ```
+pub struct MockTransport {
+    script: VecDeque<Step>,
+    responses: VecDeque<Vec<u8>>,
+    data: Option<PendingData>,
+    written: Vec<u8>,
+    downloads: Vec<Vec<u8>>,
+    data_buffer_size: usize,
+    hanging: bool,
+    cancelled: usize,
+    in_flight: VecDeque<Vec<u8>>,
+    queue_depth: usize,
+}
+
+impl Default for MockTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockTransport {
+    /// Create a mock transport with an empty script
+    pub fn new() -> Self {
+        Self {
+            script: VecDeque::new(),
+            responses: VecDeque::new(),
+            data: None,
+            written: vec![],
+            downloads: vec![],
+            data_buffer_size: DEFAULT_DATA_BUFFER_SIZE,
+            hanging: false,
+            cancelled: 0,
+            in_flight: VecDeque::new(),
+            queue_depth: 0,
+        }
+    }
+
+    /// Set the buffer size reported by [FastbootTransport::data_buffer_size]
+    pub fn set_data_buffer_size(&mut self, size: usize) {
+        self.data_buffer_size = size;
+    }
+
+    /// Number of data transfers kept in flight before the oldest one completes
+    ///
+    /// Data in flight only reaches the device once it completes or is flushed, and is lost when
+    /// transfers get cancelled. Defaults to 0, completing every transfer immediately.
+    pub fn set_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+    }
+
+    /// Expect the client to send `command`, to which the device replies with `responses`
+    pub fn expect<C, R>(&mut self, command: C, responses: R) -> &mut Self
+    where
+        C: AsRef<[u8]>,
+        R: IntoIterator<Item = FastBootResponse>,
+    {
+        self.script.push_back(Step::Command {
+            command: command.as_ref().to_vec(),
+            responses: responses.into_iter().map(|r| r.to_bytes()).collect(),
+            hang: false,
+        });
+        self
+    }
+
+    /// Expect the client to send `command`, after which the device hangs; Receiving blocks until
+    /// the next command is sent
+    pub fn expect_no_response<C>(&mut self, command: C) -> &mut Self
+    where
+        C: AsRef<[u8]>,
+    {
+        self.script.push_back(Step::Command {
+            command: command.as_ref().to_vec(),
+            responses: vec![],
+            hang: true,
+        });
+        self
+    }
+
+    /// Expect the client to send `size` bytes of data (after a `DATA` response), after which the
+    /// device replies with `responses`
+    pub fn expect_data<R>(&mut self, size: usize, responses: R) -> &mut Self
+    where
+        R: IntoIterator<Item = FastBootResponse>,
+    {
+        self.script.push_back(Step::Data {
+            size,
+            responses: responses.into_iter().map(|r| r.to_bytes()).collect(),
+        });
+        self
+    }
+
+    /// Expect the client to send an `upload` command, to which the device replies with a `DATA`
+    /// response, followed by `data` and then `responses`
+    pub fn expect_upload<D, R>(&mut self, data: D, responses: R) -> &mut Self
+    where
+        D: AsRef<[u8]>,
+        R: IntoIterator<Item = FastBootResponse>,
+    {
+        let data = data.as_ref();
+        let mut packets = vec![FastBootResponse::Data(data.len() as u64).to_bytes()];
+        if !data.is_empty() {
+            packets.push(data.to_vec());
+        }
+        packets.extend(responses.into_iter().map(|r| r.to_bytes()));
+        self.script.push_back(Step::Command {
+            command: b"upload".to_vec(),
+            responses: packets,
+            hang: false,
+        });
+        self
+    }
+
+    /// Whether all scripted steps have been executed and all responses consumed
+    pub fn is_done(&self) -> bool {
+        self.script.is_empty() && self.responses.is_empty() && self.data.is_none()
+    }
+
+    /// All bytes written by the client, both commands and data
+    pub fn written(&self) -> &[u8] {
+        &self.written
+    }
+
+    /// Data of all completed downloads
+    pub fn downloads(&self) -> &[Vec<u8>] {
+        &self.downloads
+    }
+
+    /// Number of times the client cancelled in-flight transfers
+    pub fn cancelled(&self) -> usize {
+        self.cancelled
+    }
+
+    /// Hand the oldest in-flight data transfer to the device
+    fn complete_transfer(&mut self) -> io::Result<()> {
+        let Some(data) = self.in_flight.pop_front() else {
+            return Ok(());
+        };
+        self.receive_data(&data)
+    }
+
+    fn receive_data(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut pending = match self.data.take() {
+            Some(pending) => pending,
+            None => match self.script.pop_front() {
+                Some(Step::Data { size, responses }) => PendingData {
+                    size,
+                    data: vec![],
+                    responses,
+                },
+                _ => return Err(Self::unexpected("Unexpected data".to_string())),
+            },
+        };
+
+        pending.data.extend_from_slice(data);
+        if pending.data.len() > pending.size {
+            return Err(Self::unexpected(format!(
+                "Too much data: expected {}, got {}",
+                pending.size,
+                pending.data.len()
+            )));
+        }
+        if pending.data.len() == pending.size {
+            // Responses are only sent once all data has been received
+            self.responses.extend(pending.responses);
+            self.downloads.push(pending.data);
+        } else {
+            self.data = Some(pending);
+        }
+        Ok(())
+    }
+
+    /// Whether the device is waiting for data
+    fn in_data_phase(&self) -> bool {
+        self.data.is_some() || matches!(self.script.front(), Some(Step::Data { .. }))
+    }
+
+    fn unexpected(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    }
+}
+
+impl FastbootTransport for MockTransport {
+    async fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.in_data_phase() {
+            // Like a real device, commands sent during the DATA phase are taken as data
+            self.written.extend_from_slice(packet);
+            return self.receive_data(packet);
+        }
+        self.written.extend_from_slice(packet);
+        match self.script.pop_front() {
+            Some(Step::Command {
+                command,
+                responses,
+                hang,
+            }) if command == packet => {
+                self.responses.extend(responses);
+                self.hanging = hang;
+                Ok(())
+            }
+            Some(Step::Command { command, .. }) => Err(Self::unexpected(format!(
+                "Unexpected command: {}, expected: {}",
+                String::from_utf8_lossy(packet),
+                String::from_utf8_lossy(&command)
+            ))),
+            Some(Step::Data { .. }) => unreachable!("Data is handled above"),
+            None => Err(Self::unexpected(format!(
+                "Unexpected command: {}, script is done",
+                String::from_utf8_lossy(packet),
+            ))),
+        }
+    }
+
+    async fn receive_packet(&mut self) -> io::Result<Vec<u8>> {
+        // A device waiting for data doesn't respond
+        if self.hanging || (self.responses.is_empty() && self.in_data_phase()) {
+            std::future::pending::<()>().await;
+        }
+        self.responses
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "No response scripted"))
+    }
+
+    async fn read_data(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        let mut data = self.receive_packet().await?;
+        if data.len() > max {
+            self.responses.push_front(data.split_off(max));
+        }
+        Ok(data)
+    }
+
+    fn data_buffer_size(&self) -> usize {
+        self.data_buffer_size
+    }
+
+    async fn write_data(&mut self, data: Vec<u8>) -> io::Result<()> {
+        self.written.extend_from_slice(&data);
+        self.in_flight.push_back(data);
+        while self.in_flight.len() > self.queue_depth {
+            self.complete_transfer()?;
+        }
+        Ok(())
+    }
+
+    async fn flush_data(&mut self) -> io::Result<()> {
+        while !self.in_flight.is_empty() {
+            self.complete_transfer()?;
+        }
+        Ok(())
+    }
+
+    fn pending_data(&self) -> usize {
+        self.in_flight.iter().map(Vec::len).sum()
+    }
+
+    fn cancel_transfers(&mut self) {
+        // Cancelled transfers never reach the device; It stays in the DATA phase if it was in one
+        self.in_flight.clear();
+        self.cancelled += 1;
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Display, num::ParseIntError};
+use std::{borrow::Cow, fmt::Display, num::ParseIntError, str::FromStr};
 use thiserror::Error;
 use tracing::trace;
 
@@ -18,19 +18,98 @@ pub fn parse_u64_hex(hex: &str) -> Result<u64, ParseIntError> {
     u64::from_str_radix(hex, 16)
 }
 
+/// Error parsing a size
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Invalid size: {0}")]
+pub struct ParseSizeError(String);
+
+/// Parse a size as reported by devices into a u64
+///
+/// Accepts 0x prefixed hexadecimal (e.g. `0x1234`) as well as decimal values. Decimal values may
+/// have a binary `K`, `M`, `G` or `T` suffix (optionally followed by `B`), e.g. `512M`.
+pub fn parse_size(size: &str) -> Result<u64, ParseSizeError> {
+    let err = || ParseSizeError(size.to_string());
+    let trimmed = size.trim();
+    if let Some(hex) = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+    {
+        // from_str_radix accepts a leading sign
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(err());
+        }
+        return u64::from_str_radix(hex, 16).map_err(|_| err());
+    }
+
+    let upper = trimmed.to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, shift) = match digits.as_bytes().last() {
+        Some(b'K') => (&digits[..digits.len() - 1], 10),
+        Some(b'M') => (&digits[..digits.len() - 1], 20),
+        Some(b'G') => (&digits[..digits.len() - 1], 30),
+        Some(b'T') => (&digits[..digits.len() - 1], 40),
+        _ => (digits, 0),
+    };
+    // Plain decimal values can't have a B suffix
+    if shift == 0 && digits.len() != upper.len() {
+        return Err(err());
+    }
+    let digits = digits.trim_end();
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(err());
+    }
+    let value: u64 = digits.parse().map_err(|_| err())?;
+    value.checked_mul(1 << shift).ok_or_else(err)
+}
+
+/// Format a size for a download command or DATA response; 8 hex digits if it fits in 32 bits,
+/// otherwise 16 hex digits
+fn format_size(size: u64) -> String {
+    if size <= u32::MAX as u64 {
+        format!("{size:08x}")
+    } else {
+        format!("{size:016x}")
+    }
+}
+
 /// Fastboot commands
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FastBootCommand<S> {
     /// Get a variable value
     GetVar(S),
     /// Download a given length of data to the devices
-    Download(u32),
+    ///
+    /// Sizes that fit in 32 bits are sent with 8 hex digits, larger sizes with 16 hex digits which
+    /// are only understood by devices advertising a `max-download-size` above 4GiB
+    Download(u64),
     /// Verify
     Verify(u32),
+    /// Upload data staged on the device to the host
+    Upload,
     /// Flash downloaded to a partition
     Flash(S),
     /// Erase a partition
     Erase(S),
+    /// Set the active slot
+    SetActive(S),
+    /// Create a logical partition of the given size in bytes
+    CreateLogicalPartition(S, u64),
+    /// Delete a logical partition
+    DeleteLogicalPartition(S),
+    /// Resize a logical partition to the given size in bytes
+    ResizeLogicalPartition(S, u64),
+    /// Update the super partition metadata from the downloaded data, optionally wiping it
+    UpdateSuper(S, bool),
+    /// Cancel a pending snapshot update
+    SnapshotUpdateCancel,
+    /// Merge a pending snapshot update
+    SnapshotUpdateMerge,
+    /// Wipe the installed GSI
+    GsiWipe,
+    /// Disable the installed GSI
+    GsiDisable,
+    /// Query the status of the GSI
+    GsiStatus,
     /// Boot the downloaded data
     Boot,
     /// Continue booting
@@ -39,27 +118,229 @@ pub enum FastBootCommand<S> {
     Reboot,
     /// Reboot into the bootloader
     RebootBootloader,
+    /// Reboot into recovery
+    RebootRecovery,
+    /// Reboot into userspace fastboot
+    RebootFastboot,
+    /// Reboot into a named target
+    RebootTarget(S),
     /// Power off the device
     Powerdown,
+    /// Lock the bootloader
+    FlashingLock,
+    /// Unlock the bootloader
+    FlashingUnlock,
+    /// Lock bootloader critical partitions
+    FlashingLockCritical,
+    /// Unlock bootloader critical partitions
+    FlashingUnlockCritical,
+    /// Query whether the bootloader can be unlocked
+    FlashingGetUnlockAbility,
+    /// OEM specific command
+    Oem(S),
+    /// Any other command, kept verbatim
+    Unknown(S),
 }
 
 impl<S: Display> Display for FastBootCommand<S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             FastBootCommand::GetVar(var) => write!(f, "getvar:{var}"),
-            FastBootCommand::Download(size) => write!(f, "download:{size:08x}"),
-            FastBootCommand::Verify(part) => write!(f, "verity:{part}"),
+            FastBootCommand::Download(size) => write!(f, "download:{}", format_size(*size)),
+            FastBootCommand::Verify(size) => write!(f, "verify:{size:08x}"),
+            FastBootCommand::Upload => write!(f, "upload"),
             FastBootCommand::Flash(part) => write!(f, "flash:{part}"),
             FastBootCommand::Erase(part) => write!(f, "erase:{part}"),
+            FastBootCommand::SetActive(slot) => write!(f, "set_active:{slot}"),
+            FastBootCommand::CreateLogicalPartition(part, size) => {
+                write!(f, "create-logical-partition:{part}:{size}")
+            }
+            FastBootCommand::DeleteLogicalPartition(part) => {
+                write!(f, "delete-logical-partition:{part}")
+            }
+            FastBootCommand::ResizeLogicalPartition(part, size) => {
+                write!(f, "resize-logical-partition:{part}:{size}")
+            }
+            FastBootCommand::UpdateSuper(part, false) => write!(f, "update-super:{part}"),
+            FastBootCommand::UpdateSuper(part, true) => write!(f, "update-super:{part}:wipe"),
+            FastBootCommand::SnapshotUpdateCancel => write!(f, "snapshot-update:cancel"),
+            FastBootCommand::SnapshotUpdateMerge => write!(f, "snapshot-update:merge"),
+            FastBootCommand::GsiWipe => write!(f, "gsi:wipe"),
+            FastBootCommand::GsiDisable => write!(f, "gsi:disable"),
+            FastBootCommand::GsiStatus => write!(f, "gsi:status"),
             FastBootCommand::Boot => write!(f, "boot"),
             FastBootCommand::Continue => write!(f, "continue"),
             FastBootCommand::Reboot => write!(f, "reboot"),
             FastBootCommand::RebootBootloader => write!(f, "reboot-bootloader"),
+            FastBootCommand::RebootRecovery => write!(f, "reboot-recovery"),
+            FastBootCommand::RebootFastboot => write!(f, "reboot-fastboot"),
+            FastBootCommand::RebootTarget(target) => write!(f, "reboot:{target}"),
             FastBootCommand::Powerdown => write!(f, "powerdown"),
+            FastBootCommand::FlashingLock => write!(f, "flashing lock"),
+            FastBootCommand::FlashingUnlock => write!(f, "flashing unlock"),
+            FastBootCommand::FlashingLockCritical => write!(f, "flashing lock_critical"),
+            FastBootCommand::FlashingUnlockCritical => write!(f, "flashing unlock_critical"),
+            FastBootCommand::FlashingGetUnlockAbility => write!(f, "flashing get_unlock_ability"),
+            FastBootCommand::Oem(cmd) => write!(f, "oem {cmd}"),
+            FastBootCommand::Unknown(cmd) => write!(f, "{cmd}"),
+        }
+    }
+}
+
+/// Parse errors for fastboot commands
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FastBootCommandParseError {
+    /// Command is not valid utf-8
+    #[error("Command is not valid utf-8")]
+    InvalidUtf8,
+    /// Couldn't parse the size argument
+    #[error("Couldn't parse size argument")]
+    Size,
+    /// A required argument is missing
+    #[error("Missing command argument")]
+    MissingArgument,
+}
+
+impl<'a> FastBootCommand<&'a str> {
+    /// Parse a fastboot command as sent by the host
+    ///
+    /// Commands that are not known are returned as [FastBootCommand::Unknown]
+    pub fn parse(cmd: &'a str) -> Result<Self, FastBootCommandParseError> {
+        // from_str_radix accepts a leading sign, which isn't valid on the wire
+        let check_hex = |size: &str| {
+            if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(FastBootCommandParseError::Size);
+            }
+            Ok(())
+        };
+        let parse_size = |size: &str| {
+            check_hex(size)?;
+            u32::from_str_radix(size, 16).map_err(|_| FastBootCommandParseError::Size)
+        };
+        let parse_download_size = |size: &str| {
+            check_hex(size)?;
+            if size.len() > 16 {
+                return Err(FastBootCommandParseError::Size);
+            }
+            u64::from_str_radix(size, 16).map_err(|_| FastBootCommandParseError::Size)
+        };
+        // Logical partition sizes are in decimal
+        let partition_size = |args: &'a str| {
+            let (part, size) = args
+                .rsplit_once(':')
+                .ok_or(FastBootCommandParseError::MissingArgument)?;
+            let size = size.parse().map_err(|_| FastBootCommandParseError::Size)?;
+            Ok((part, size))
+        };
+        let cmd = match cmd.split_once(':') {
+            Some(("getvar", var)) => Self::GetVar(var),
+            Some(("download", size)) => Self::Download(parse_download_size(size)?),
+            Some(("verify", size)) => Self::Verify(parse_size(size)?),
+            Some(("flash", part)) => Self::Flash(part),
+            Some(("erase", part)) => Self::Erase(part),
+            Some(("set_active", slot)) => Self::SetActive(slot),
+            Some(("create-logical-partition", args)) => {
+                let (part, size) = partition_size(args)?;
+                Self::CreateLogicalPartition(part, size)
+            }
+            Some(("delete-logical-partition", part)) => Self::DeleteLogicalPartition(part),
+            Some(("resize-logical-partition", args)) => {
+                let (part, size) = partition_size(args)?;
+                Self::ResizeLogicalPartition(part, size)
+            }
+            Some(("snapshot-update", "cancel")) => Self::SnapshotUpdateCancel,
+            Some(("snapshot-update", "merge")) => Self::SnapshotUpdateMerge,
+            Some(("gsi", "wipe")) => Self::GsiWipe,
+            Some(("gsi", "disable")) => Self::GsiDisable,
+            Some(("gsi", "status")) => Self::GsiStatus,
+            Some(("update-super", args)) => match args.strip_suffix(":wipe") {
+                Some(part) => Self::UpdateSuper(part, true),
+                None => Self::UpdateSuper(args, false),
+            },
+            Some(("reboot", target)) => Self::RebootTarget(target),
+            _ => match cmd {
+                "upload" => Self::Upload,
+                "boot" => Self::Boot,
+                "continue" => Self::Continue,
+                "reboot" => Self::Reboot,
+                "reboot-bootloader" => Self::RebootBootloader,
+                "reboot-recovery" => Self::RebootRecovery,
+                "reboot-fastboot" => Self::RebootFastboot,
+                "powerdown" => Self::Powerdown,
+                "flashing lock" => Self::FlashingLock,
+                "flashing unlock" => Self::FlashingUnlock,
+                "flashing lock_critical" => Self::FlashingLockCritical,
+                "flashing unlock_critical" => Self::FlashingUnlockCritical,
+                "flashing get_unlock_ability" => Self::FlashingGetUnlockAbility,
+                _ => match cmd.strip_prefix("oem ") {
+                    Some(oem) => Self::Oem(oem),
+                    None => Self::Unknown(cmd),
+                },
+            },
+        };
+        Ok(cmd)
+    }
+
+    /// Parse a fastboot command from the raw bytes sent by the host
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, FastBootCommandParseError> {
+        let cmd = std::str::from_utf8(bytes).map_err(|_| FastBootCommandParseError::InvalidUtf8)?;
+        Self::parse(cmd)
+    }
+}
+
+impl<S> FastBootCommand<S> {
+    /// Convert the string arguments of the command
+    pub fn map<T, F: FnOnce(S) -> T>(self, f: F) -> FastBootCommand<T> {
+        match self {
+            FastBootCommand::GetVar(var) => FastBootCommand::GetVar(f(var)),
+            FastBootCommand::Download(size) => FastBootCommand::Download(size),
+            FastBootCommand::Verify(size) => FastBootCommand::Verify(size),
+            FastBootCommand::Upload => FastBootCommand::Upload,
+            FastBootCommand::Flash(part) => FastBootCommand::Flash(f(part)),
+            FastBootCommand::Erase(part) => FastBootCommand::Erase(f(part)),
+            FastBootCommand::SetActive(slot) => FastBootCommand::SetActive(f(slot)),
+            FastBootCommand::CreateLogicalPartition(part, size) => {
+                FastBootCommand::CreateLogicalPartition(f(part), size)
+            }
+            FastBootCommand::DeleteLogicalPartition(part) => {
+                FastBootCommand::DeleteLogicalPartition(f(part))
+            }
+            FastBootCommand::ResizeLogicalPartition(part, size) => {
+                FastBootCommand::ResizeLogicalPartition(f(part), size)
+            }
+            FastBootCommand::UpdateSuper(part, wipe) => FastBootCommand::UpdateSuper(f(part), wipe),
+            FastBootCommand::SnapshotUpdateCancel => FastBootCommand::SnapshotUpdateCancel,
+            FastBootCommand::SnapshotUpdateMerge => FastBootCommand::SnapshotUpdateMerge,
+            FastBootCommand::GsiWipe => FastBootCommand::GsiWipe,
+            FastBootCommand::GsiDisable => FastBootCommand::GsiDisable,
+            FastBootCommand::GsiStatus => FastBootCommand::GsiStatus,
+            FastBootCommand::Boot => FastBootCommand::Boot,
+            FastBootCommand::Continue => FastBootCommand::Continue,
+            FastBootCommand::Reboot => FastBootCommand::Reboot,
+            FastBootCommand::RebootBootloader => FastBootCommand::RebootBootloader,
+            FastBootCommand::RebootRecovery => FastBootCommand::RebootRecovery,
+            FastBootCommand::RebootFastboot => FastBootCommand::RebootFastboot,
+            FastBootCommand::RebootTarget(target) => FastBootCommand::RebootTarget(f(target)),
+            FastBootCommand::Powerdown => FastBootCommand::Powerdown,
+            FastBootCommand::FlashingLock => FastBootCommand::FlashingLock,
+            FastBootCommand::FlashingUnlock => FastBootCommand::FlashingUnlock,
+            FastBootCommand::FlashingLockCritical => FastBootCommand::FlashingLockCritical,
+            FastBootCommand::FlashingUnlockCritical => FastBootCommand::FlashingUnlockCritical,
+            FastBootCommand::FlashingGetUnlockAbility => FastBootCommand::FlashingGetUnlockAbility,
+            FastBootCommand::Oem(cmd) => FastBootCommand::Oem(f(cmd)),
+            FastBootCommand::Unknown(cmd) => FastBootCommand::Unknown(f(cmd)),
         }
     }
 }
 
+impl FromStr for FastBootCommand<String> {
+    type Err = FastBootCommandParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FastBootCommand::parse(s).map(|cmd| cmd.map(String::from))
+    }
+}
+
 /// Parse errors for fastboot responses
 #[derive(Error, Debug, PartialEq, Eq)]
 pub enum FastBootResponseParseError {
@@ -69,6 +350,87 @@ pub enum FastBootResponseParseError {
     /// Couldn't parse DATA length
     #[error("Couldn't parse DATA length")]
     DataLength,
+    /// Response payload is not valid utf-8
+    #[error("Response is not valid utf-8")]
+    InvalidUtf8,
+}
+
+/// Type of a fastboot response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastBootResponseKind {
+    Okay,
+    Info,
+    Text,
+    Fail,
+    Data,
+}
+
+/// Fastboot response with the payload kept as raw bytes
+///
+/// Devices are not guaranteed to send valid utf-8 (e.g. in vendor specific INFO messages); This
+/// allows inspecting such responses without loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RawFastBootResponse<'a> {
+    /// Type of the response
+    pub kind: FastBootResponseKind,
+    /// Payload following the 4 byte response type
+    pub payload: &'a [u8],
+}
+
+impl<'a> RawFastBootResponse<'a> {
+    /// Split a response into its type and payload
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, FastBootResponseParseError> {
+        let Some((kind, payload)) = bytes.split_first_chunk::<4>() else {
+            return Err(FastBootResponseParseError::UnknownReply);
+        };
+        let kind = match kind {
+            b"OKAY" => FastBootResponseKind::Okay,
+            b"INFO" => FastBootResponseKind::Info,
+            b"TEXT" => FastBootResponseKind::Text,
+            b"FAIL" => FastBootResponseKind::Fail,
+            b"DATA" => FastBootResponseKind::Data,
+            _ => return Err(FastBootResponseParseError::UnknownReply),
+        };
+        Ok(Self { kind, payload })
+    }
+
+    /// Payload decoded as utf-8, replacing invalid sequences with U+FFFD
+    pub fn payload_lossy(&self) -> Cow<'a, str> {
+        String::from_utf8_lossy(self.payload)
+    }
+
+    /// Decode into a [FastBootResponse], failing if the payload isn't valid utf-8
+    pub fn decode(&self) -> Result<FastBootResponse, FastBootResponseParseError> {
+        let payload = std::str::from_utf8(self.payload)
+            .map_err(|_| FastBootResponseParseError::InvalidUtf8)?;
+        self.decode_payload(payload)
+    }
+
+    /// Decode into a [FastBootResponse], replacing invalid utf-8 sequences with U+FFFD
+    pub fn decode_lossy(&self) -> Result<FastBootResponse, FastBootResponseParseError> {
+        self.decode_payload(&self.payload_lossy())
+    }
+
+    fn decode_payload(&self, data: &str) -> Result<FastBootResponse, FastBootResponseParseError> {
+        trace!("Parsing Response: {:?} {}", self.kind, data);
+        match self.kind {
+            FastBootResponseKind::Okay => Ok(FastBootResponse::Okay(data.into())),
+            FastBootResponseKind::Info => Ok(FastBootResponse::Info(data.into())),
+            FastBootResponseKind::Text => Ok(FastBootResponse::Text(data.into())),
+            FastBootResponseKind::Fail => Ok(FastBootResponse::Fail(data.into())),
+            FastBootResponseKind::Data => {
+                if data.is_empty()
+                    || data.len() > 16
+                    || !data.bytes().all(|b| b.is_ascii_hexdigit())
+                {
+                    return Err(FastBootResponseParseError::DataLength);
+                }
+                let size = u64::from_str_radix(data, 16)
+                    .or(Err(FastBootResponseParseError::DataLength))?;
+                Ok(FastBootResponse::Data(size))
+            }
+        }
+    }
 }
 
 /// Fastboot response
@@ -83,36 +445,34 @@ pub enum FastBootResponse {
     /// Command failed with provided reason
     Fail(String),
     /// Device expected the amount of data to be sent
-    Data(u32),
-}
-
-impl<'a> FastBootResponse {
-    fn from_parts(resp: &str, data: &'a str) -> Result<Self, FastBootResponseParseError> {
-        trace!("Parsing Response: {} {}", resp, data);
-        match resp {
-            "OKAY" => Ok(Self::Okay(data.into())),
-            "INFO" => Ok(Self::Info(data.into())),
-            "TEXT" => Ok(Self::Text(data.into())),
-            "FAIL" => Ok(Self::Fail(data.into())),
-            "DATA" => {
-                let offset = u32::from_str_radix(data, 16)
-                    .or(Err(FastBootResponseParseError::DataLength))?;
-                Ok(Self::Data(offset))
-            }
-            _ => Err(FastBootResponseParseError::UnknownReply),
-        }
-    }
+    Data(u64),
+}
 
+impl FastBootResponse {
     /// Parse a fastboot response from provided data
-    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self, FastBootResponseParseError> {
-        if bytes.len() < 4 {
-            Err(FastBootResponseParseError::UnknownReply)
-        } else {
-            let resp = std::str::from_utf8(&bytes[0..4]).unwrap();
-            let data = std::str::from_utf8(&bytes[4..]).unwrap();
+    ///
+    /// Fails with [FastBootResponseParseError::InvalidUtf8] if the payload isn't valid utf-8, see
+    /// [FastBootResponse::from_bytes_lossy] and [RawFastBootResponse] for alternatives.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, FastBootResponseParseError> {
+        RawFastBootResponse::from_bytes(bytes)?.decode()
+    }
+
+    /// Parse a fastboot response from provided data, replacing invalid utf-8 sequences in the
+    /// payload with U+FFFD
+    pub fn from_bytes_lossy(bytes: &[u8]) -> Result<Self, FastBootResponseParseError> {
+        RawFastBootResponse::from_bytes(bytes)?.decode_lossy()
+    }
 
-            Self::from_parts(resp, data)
+    /// Serialize the fastboot response as sent by the device
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Okay(value) => format!("OKAY{value}"),
+            Self::Info(info) => format!("INFO{info}"),
+            Self::Text(text) => format!("TEXT{text}"),
+            Self::Fail(reason) => format!("FAIL{reason}"),
+            Self::Data(size) => format!("DATA{}", format_size(*size)),
         }
+        .into_bytes()
     }
 }
 
@@ -146,6 +506,140 @@ mod test {
         parse_u32_hex("123456").unwrap_err();
     }
 
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0x1000"), Ok(0x1000));
+        assert_eq!(parse_size("0X0000000100000000"), Ok(0x1_0000_0000));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size(" 4096 "), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("256mb"), Ok(256 * 1024 * 1024));
+        assert_eq!(parse_size("8G"), Ok(8 << 30));
+        assert_eq!(parse_size("2 TB"), Ok(2 << 40));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("0x").is_err());
+        assert!(parse_size("1234B").is_err());
+        assert!(parse_size("12X").is_err());
+        assert!(parse_size("0xfffffffffffffffff").is_err());
+        assert!(parse_size("17179869184T").is_err());
+        assert!(parse_size("0x+10").is_err());
+        assert!(parse_size("0x-10").is_err());
+        assert!(parse_size("+5").is_err());
+        assert!(parse_size("-5").is_err());
+        assert!(parse_size("+5M").is_err());
+    }
+
+    #[test]
+    fn command_roundtrip() {
+        let commands = [
+            FastBootCommand::GetVar("version"),
+            FastBootCommand::GetVar("partition-size:system_a"),
+            FastBootCommand::Download(0x123456),
+            FastBootCommand::Download(0x1_2345_6789),
+            FastBootCommand::Verify(0x1000),
+            FastBootCommand::Upload,
+            FastBootCommand::Flash("boot_a"),
+            FastBootCommand::Erase("userdata"),
+            FastBootCommand::SetActive("b"),
+            FastBootCommand::CreateLogicalPartition("system_a", 1 << 32),
+            FastBootCommand::DeleteLogicalPartition("product_b"),
+            FastBootCommand::ResizeLogicalPartition("vendor_a", 12345),
+            FastBootCommand::UpdateSuper("super", false),
+            FastBootCommand::UpdateSuper("super", true),
+            FastBootCommand::SnapshotUpdateCancel,
+            FastBootCommand::SnapshotUpdateMerge,
+            FastBootCommand::GsiWipe,
+            FastBootCommand::GsiDisable,
+            FastBootCommand::GsiStatus,
+            FastBootCommand::Boot,
+            FastBootCommand::Continue,
+            FastBootCommand::Reboot,
+            FastBootCommand::RebootBootloader,
+            FastBootCommand::RebootRecovery,
+            FastBootCommand::RebootFastboot,
+            FastBootCommand::RebootTarget("edl"),
+            FastBootCommand::Powerdown,
+            FastBootCommand::FlashingLock,
+            FastBootCommand::FlashingUnlock,
+            FastBootCommand::FlashingLockCritical,
+            FastBootCommand::FlashingUnlockCritical,
+            FastBootCommand::FlashingGetUnlockAbility,
+            FastBootCommand::Oem("device-info"),
+            FastBootCommand::Unknown("flashing unlock_bootloader"),
+        ];
+        for cmd in commands {
+            let s = cmd.to_string();
+            assert_eq!(FastBootCommand::parse(&s).unwrap(), cmd);
+            assert_eq!(FastBootCommand::from_bytes(s.as_bytes()).unwrap(), cmd);
+            let owned: FastBootCommand<String> = s.parse().unwrap();
+            assert_eq!(owned, cmd.map(String::from));
+        }
+    }
+
+    #[test]
+    fn command_parse() {
+        assert_eq!(
+            FastBootCommand::parse("download:00001000").unwrap(),
+            FastBootCommand::Download(0x1000)
+        );
+        assert_eq!(
+            FastBootCommand::parse("getvar:all").unwrap(),
+            FastBootCommand::GetVar("all")
+        );
+        assert_eq!(
+            FastBootCommand::parse("oem unlock go").unwrap(),
+            FastBootCommand::Oem("unlock go")
+        );
+        assert_eq!(
+            FastBootCommand::parse("flashing unlock").unwrap(),
+            FastBootCommand::FlashingUnlock
+        );
+        assert_eq!(
+            FastBootCommand::parse("gsi:install").unwrap(),
+            FastBootCommand::Unknown("gsi:install")
+        );
+    }
+
+    #[test]
+    fn command_parse_invalid() {
+        assert_eq!(
+            FastBootCommand::parse("download:xyz").unwrap_err(),
+            FastBootCommandParseError::Size
+        );
+        assert_eq!(
+            FastBootCommand::parse("download:00000000000000001").unwrap_err(),
+            FastBootCommandParseError::Size
+        );
+        assert_eq!(
+            FastBootCommand::parse("verify:100000000").unwrap_err(),
+            FastBootCommandParseError::Size
+        );
+        assert_eq!(
+            FastBootCommand::parse("download:+0001000").unwrap_err(),
+            FastBootCommandParseError::Size
+        );
+        assert_eq!(
+            FastBootCommand::parse("verify:+10").unwrap_err(),
+            FastBootCommandParseError::Size
+        );
+        assert_eq!(
+            FastBootCommand::parse("download:").unwrap_err(),
+            FastBootCommandParseError::Size
+        );
+        assert_eq!(
+            FastBootCommand::parse("resize-logical-partition:system_a").unwrap_err(),
+            FastBootCommandParseError::MissingArgument
+        );
+        assert_eq!(
+            FastBootCommand::parse("create-logical-partition:system_a:0x1000").unwrap_err(),
+            FastBootCommandParseError::Size
+        );
+        assert_eq!(
+            FastBootCommand::from_bytes(b"getvar:\xff").unwrap_err(),
+            FastBootCommandParseError::InvalidUtf8
+        );
+    }
+
     #[test]
     fn response_parse_ok() {
         let r = FastBootResponse::from_bytes(b"OKAYtest").unwrap();
@@ -175,6 +669,32 @@ mod test {
         assert_eq!(r, FastBootResponse::Data(0x123456));
     }
 
+    #[test]
+    fn response_roundtrip() {
+        for r in [
+            FastBootResponse::Okay("0.4".to_string()),
+            FastBootResponse::Info("test".to_string()),
+            FastBootResponse::Text("test".to_string()),
+            FastBootResponse::Fail("failed".to_string()),
+            FastBootResponse::Data(0x123456),
+        ] {
+            assert_eq!(FastBootResponse::from_bytes(&r.to_bytes()).unwrap(), r);
+        }
+        assert_eq!(FastBootResponse::Data(0x1234).to_bytes(), b"DATA00001234");
+        assert_eq!(
+            FastBootResponse::Data(0x1_0000_0000).to_bytes(),
+            b"DATA0000000100000000"
+        );
+        assert_eq!(
+            FastBootResponse::from_bytes(b"DATA0000000100000000").unwrap(),
+            FastBootResponse::Data(0x1_0000_0000)
+        );
+        assert_eq!(
+            FastBootResponse::from_bytes(b"DATA00000000000000001").unwrap_err(),
+            FastBootResponseParseError::DataLength
+        );
+    }
+
     #[test]
     fn response_parse_invalid() {
         let e = FastBootResponse::from_bytes(b"UNKN").unwrap_err();
@@ -186,4 +706,40 @@ mod test {
         let e = FastBootResponse::from_bytes(b"UN").unwrap_err();
         assert_eq!(e, FastBootResponseParseError::UnknownReply);
     }
+
+    #[test]
+    fn response_parse_invalid_utf8() {
+        let bytes = b"INFOvendor \xff\xfe info";
+        let e = FastBootResponse::from_bytes(bytes).unwrap_err();
+        assert_eq!(e, FastBootResponseParseError::InvalidUtf8);
+
+        let r = FastBootResponse::from_bytes_lossy(bytes).unwrap();
+        assert_eq!(
+            r,
+            FastBootResponse::Info("vendor \u{fffd}\u{fffd} info".to_string())
+        );
+
+        let raw = RawFastBootResponse::from_bytes(bytes).unwrap();
+        assert_eq!(raw.kind, FastBootResponseKind::Info);
+        assert_eq!(raw.payload, b"vendor \xff\xfe info");
+
+        let e = FastBootResponse::from_bytes(b"\xff\xfe\xfd\xfctest").unwrap_err();
+        assert_eq!(e, FastBootResponseParseError::UnknownReply);
+        let e = FastBootResponse::from_bytes_lossy(b"DATA\xff").unwrap_err();
+        assert_eq!(e, FastBootResponseParseError::DataLength);
+    }
+
+    #[test]
+    fn response_parse_data_invalid() {
+        for data in [
+            &b"DATA"[..],
+            b"DATA+1",
+            b"DATA-1",
+            b"DATA 1",
+            b"DATA00000000000000001",
+        ] {
+            let e = FastBootResponse::from_bytes(data).unwrap_err();
+            assert_eq!(e, FastBootResponseParseError::DataLength);
+        }
+    }
 }
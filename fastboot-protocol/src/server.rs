@@ -0,0 +1,379 @@
+use std::{future::Future, io};
+
+use thiserror::Error;
+use tracing::{instrument, trace};
+
+use crate::protocol::{FastBootCommand, FastBootResponse};
+use crate::transport::FastbootTransport;
+
+/// Result of a [FastbootHandler] operation; The error is reported to the host as the FAIL reason
+pub type HandlerResult<T> = Result<T, String>;
+
+/// Device side fastboot command handler
+///
+/// The [FastbootServer] parses the commands from the host and dispatches them to the handler.
+/// Apart from [FastbootHandler::get_var] all operations are refused by default.
+pub trait FastbootHandler: Send {
+    /// Get the value of a variable
+    fn get_var(&mut self, var: &str) -> impl Future<Output = HandlerResult<String>> + Send;
+
+    /// Get all variables and their values, used for `getvar:all`
+    fn get_all_vars(
+        &mut self,
+    ) -> impl Future<Output = HandlerResult<Vec<(String, String)>>> + Send {
+        async { Err("getvar:all is not supported".to_string()) }
+    }
+
+    /// Prepare for a download of `size` bytes
+    ///
+    /// When accepted the downloaded data will be passed to [FastbootHandler::download_data]
+    fn download(&mut self, _size: u64) -> impl Future<Output = HandlerResult<()>> + Send {
+        async { Err("Download is not supported".to_string()) }
+    }
+
+    /// Sink for the data of an accepted download
+    ///
+    /// Called for every block of data received from the host. Once a block is refused the
+    /// remaining data is discarded and the download fails
+    fn download_data(&mut self, _data: &[u8]) -> impl Future<Output = HandlerResult<()>> + Send {
+        async { Err("Download is not supported".to_string()) }
+    }
+
+    /// Flash the downloaded data to a partition
+    fn flash(&mut self, _partition: &str) -> impl Future<Output = HandlerResult<()>> + Send {
+        async { Err("Flashing is not supported".to_string()) }
+    }
+
+    /// Erase a partition
+    fn erase(&mut self, _partition: &str) -> impl Future<Output = HandlerResult<()>> + Send {
+        async { Err("Erasing is not supported".to_string()) }
+    }
+
+    /// Mark a slot as active
+    fn set_active(&mut self, _slot: &str) -> impl Future<Output = HandlerResult<()>> + Send {
+        async { Err("Slots are not supported".to_string()) }
+    }
+
+    /// Data to send to the host for an `upload` command, e.g. data staged by an OEM command
+    fn upload(&mut self) -> impl Future<Output = HandlerResult<Vec<u8>>> + Send {
+        async { Err("Upload is not supported".to_string()) }
+    }
+
+    /// Execute an OEM specific command, returning the OKAY value
+    fn oem(&mut self, command: &str) -> impl Future<Output = HandlerResult<String>> + Send {
+        let err = format!("Unknown OEM command: {command}");
+        async { Err(err) }
+    }
+
+    /// Execute a `flashing` command, e.g. `lock` or `get_unlock_ability`, returning the OKAY value
+    fn flashing(&mut self, command: &str) -> impl Future<Output = HandlerResult<String>> + Send {
+        let err = format!("Unknown flashing command: {command}");
+        async { Err(err) }
+    }
+
+    /// Reboot the device; `target` is e.g. `bootloader` for `reboot-bootloader`
+    ///
+    /// The host is only told the result after this function returns, so the actual reboot should
+    /// be deferred until after [FastbootServer::run] returned.
+    fn reboot(&mut self, _target: Option<&str>) -> impl Future<Output = HandlerResult<()>> + Send {
+        async { Err("Rebooting is not supported".to_string()) }
+    }
+}
+
+/// Fastboot server errors
+#[derive(Debug, Error)]
+pub enum FastbootServerError {
+    #[error("Transport error: {0}")]
+    Transport(#[from] std::io::Error),
+    #[error("Host sent more data than announced")]
+    TooMuchData,
+}
+
+/// Device side fastboot server
+///
+/// Receives commands over a [FastbootTransport], dispatches them to a [FastbootHandler] and sends
+/// back the responses. Any transport can be used, e.g. [crate::tcp::TcpTransport::accept_stream]
+/// for fastboot over TCP or an implementation on top of USB FunctionFS endpoints.
+pub struct FastbootServer<T, H> {
+    transport: T,
+    handler: H,
+}
+
+impl<T: FastbootTransport, H: FastbootHandler> FastbootServer<T, H> {
+    /// Create a server for the given transport and handler
+    pub fn new(transport: T, handler: H) -> Self {
+        Self { transport, handler }
+    }
+
+    /// Reference to the handler
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    /// Mutable reference to the handler
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Consume the server returning the transport and handler
+    pub fn into_parts(self) -> (T, H) {
+        (self.transport, self.handler)
+    }
+
+    /// Serve commands until the host disconnects or a reboot has been acknowledged
+    #[instrument(skip_all, err)]
+    pub async fn run(&mut self) -> Result<(), FastbootServerError> {
+        loop {
+            let packet = match self.transport.receive_packet().await {
+                Ok(packet) => packet,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    trace!("Host disconnected");
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !self.handle_command(&packet).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn respond(&mut self, response: FastBootResponse) -> Result<(), FastbootServerError> {
+        trace!("Response: {:?}", response);
+        self.transport.send_packet(&response.to_bytes()).await?;
+        Ok(())
+    }
+
+    /// Handle a single command, returning whether more commands should be served
+    async fn handle_command(&mut self, packet: &[u8]) -> Result<bool, FastbootServerError> {
+        let cmd = match FastBootCommand::from_bytes(packet) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.respond(FastBootResponse::Fail(format!("Invalid command: {e}")))
+                    .await?;
+                return Ok(true);
+            }
+        };
+        trace!("Command: {cmd}");
+
+        let result = match cmd {
+            FastBootCommand::GetVar("all") => self.get_all_vars().await?,
+            FastBootCommand::GetVar(var) => self.handler.get_var(var).await,
+            FastBootCommand::Download(size) => {
+                self.download(size).await?;
+                return Ok(true);
+            }
+            FastBootCommand::Upload => {
+                self.upload().await?;
+                return Ok(true);
+            }
+            FastBootCommand::Flash(partition) => {
+                self.handler.flash(partition).await.map(|_| String::new())
+            }
+            FastBootCommand::Erase(partition) => {
+                self.handler.erase(partition).await.map(|_| String::new())
+            }
+            FastBootCommand::SetActive(slot) => {
+                self.handler.set_active(slot).await.map(|_| String::new())
+            }
+            FastBootCommand::Oem(command) => self.handler.oem(command).await,
+            FastBootCommand::FlashingLock => self.handler.flashing("lock").await,
+            FastBootCommand::FlashingUnlock => self.handler.flashing("unlock").await,
+            FastBootCommand::FlashingLockCritical => self.handler.flashing("lock_critical").await,
+            FastBootCommand::FlashingUnlockCritical => {
+                self.handler.flashing("unlock_critical").await
+            }
+            FastBootCommand::FlashingGetUnlockAbility => {
+                self.handler.flashing("get_unlock_ability").await
+            }
+            FastBootCommand::Reboot => return self.reboot(None).await,
+            FastBootCommand::RebootBootloader => return self.reboot(Some("bootloader")).await,
+            FastBootCommand::RebootRecovery => return self.reboot(Some("recovery")).await,
+            FastBootCommand::RebootFastboot => return self.reboot(Some("fastboot")).await,
+            FastBootCommand::RebootTarget(target) => return self.reboot(Some(target)).await,
+            FastBootCommand::Unknown(cmd) if cmd.starts_with("reboot-") => {
+                return self.reboot(cmd.strip_prefix("reboot-")).await;
+            }
+            cmd => Err(format!("Unknown command: {cmd}")),
+        };
+        self.respond_result(result).await?;
+        Ok(true)
+    }
+
+    async fn reboot(&mut self, target: Option<&str>) -> Result<bool, FastbootServerError> {
+        let result = self.handler.reboot(target).await;
+        let rebooting = result.is_ok();
+        self.respond_result(result.map(|_| String::new())).await?;
+        Ok(!rebooting)
+    }
+
+    async fn respond_result(
+        &mut self,
+        result: HandlerResult<String>,
+    ) -> Result<(), FastbootServerError> {
+        match result {
+            Ok(value) => self.respond(FastBootResponse::Okay(value)).await,
+            Err(reason) => self.respond(FastBootResponse::Fail(reason)).await,
+        }
+    }
+
+    async fn get_all_vars(&mut self) -> Result<HandlerResult<String>, FastbootServerError> {
+        let vars = match self.handler.get_all_vars().await {
+            Ok(vars) => vars,
+            Err(e) => return Ok(Err(e)),
+        };
+        for (var, value) in vars {
+            self.respond(FastBootResponse::Info(format!("{var}:{value}")))
+                .await?;
+        }
+        Ok(Ok(String::new()))
+    }
+
+    async fn download(&mut self, size: u64) -> Result<(), FastbootServerError> {
+        if let Err(reason) = self.handler.download(size).await {
+            return self.respond(FastBootResponse::Fail(reason)).await;
+        }
+        self.respond(FastBootResponse::Data(size)).await?;
+
+        let mut left = size;
+        let mut result = Ok(());
+        while left > 0 {
+            let max = usize::try_from(left)
+                .unwrap_or(usize::MAX)
+                .min(self.transport.data_buffer_size());
+            let data = self.transport.read_data(max).await?;
+            if data.len() as u64 > left {
+                return Err(FastbootServerError::TooMuchData);
+            }
+            left -= data.len() as u64;
+            if result.is_ok() {
+                result = self.handler.download_data(&data).await;
+            }
+        }
+        self.respond_result(result.map(|_| String::new())).await
+    }
+
+    async fn upload(&mut self) -> Result<(), FastbootServerError> {
+        let data = match self.handler.upload().await {
+            Ok(data) => data,
+            Err(reason) => return self.respond(FastBootResponse::Fail(reason)).await,
+        };
+        self.respond(FastBootResponse::Data(data.len() as u64))
+            .await?;
+
+        for chunk in data.chunks(self.transport.data_buffer_size()) {
+            self.transport.write_data(chunk.to_vec()).await?;
+        }
+        self.transport.flush_data().await?;
+        self.respond(FastBootResponse::Okay(String::new())).await
+    }
+}
+
+#[cfg(all(test, feature = "tcp"))]
+mod test {
+    use super::*;
+    use crate::client::FastBootError;
+    use crate::tcp::{TcpFastBoot, TcpTransport};
+    use std::collections::HashMap;
+    use tokio::net::TcpListener;
+
+    #[derive(Default)]
+    struct TestDevice {
+        download: Vec<u8>,
+        partitions: HashMap<String, Vec<u8>>,
+        reboot: Option<Option<String>>,
+    }
+
+    impl FastbootHandler for TestDevice {
+        async fn get_var(&mut self, var: &str) -> HandlerResult<String> {
+            match var {
+                "version" => Ok("0.4".to_string()),
+                "max-download-size" => Ok("0x1000".to_string()),
+                _ => Err(format!("Unknown variable: {var}")),
+            }
+        }
+
+        async fn get_all_vars(&mut self) -> HandlerResult<Vec<(String, String)>> {
+            Ok(vec![
+                ("version".to_string(), "0.4".to_string()),
+                ("partition-size:boot_a".to_string(), "0x1000".to_string()),
+            ])
+        }
+
+        async fn download(&mut self, size: u64) -> HandlerResult<()> {
+            if size > 0x1000 {
+                return Err("Too big".to_string());
+            }
+            self.download.clear();
+            Ok(())
+        }
+
+        async fn download_data(&mut self, data: &[u8]) -> HandlerResult<()> {
+            self.download.extend_from_slice(data);
+            Ok(())
+        }
+
+        async fn flash(&mut self, partition: &str) -> HandlerResult<()> {
+            self.partitions
+                .insert(partition.to_string(), std::mem::take(&mut self.download));
+            Ok(())
+        }
+
+        async fn upload(&mut self) -> HandlerResult<Vec<u8>> {
+            self.partitions
+                .get("boot_a")
+                .cloned()
+                .ok_or_else(|| "Nothing staged".to_string())
+        }
+
+        async fn reboot(&mut self, target: Option<&str>) -> HandlerResult<()> {
+            self.reboot = Some(target.map(String::from));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let transport = TcpTransport::accept_stream(stream).await.unwrap();
+            let mut server = FastbootServer::new(transport, TestDevice::default());
+            server.run().await.unwrap();
+            server.into_parts().1
+        });
+
+        let mut fb = TcpFastBoot::connect(addr).await.unwrap();
+        assert_eq!(fb.get_var("version").await.unwrap(), "0.4");
+        let err = fb.get_var("unknown").await.unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+
+        let vars = fb.get_all_vars().await.unwrap();
+        assert_eq!(vars["partition-size:boot_a"], "0x1000");
+
+        let err = fb.download(0x1001).await.err().unwrap();
+        assert!(matches!(err, FastBootError::FastbootFailed(r) if r == "Too big"));
+
+        let err = fb.upload().await.err().unwrap();
+        assert!(matches!(err, FastBootError::FastbootFailed(r) if r == "Nothing staged"));
+
+        let data: Vec<u8> = (0..0x1000).map(|i| i as u8).collect();
+        let mut download = fb.download(data.len() as u64).await.unwrap();
+        download.extend_from_slice(&data).await.unwrap();
+        download.finish().await.unwrap();
+        fb.flash("boot_a").await.unwrap();
+
+        let upload = fb.upload().await.unwrap();
+        assert_eq!(upload.read_to_end().await.unwrap(), data);
+
+        let err = fb.erase("boot_a").await.unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+
+        fb.reboot_bootloader().await.unwrap();
+
+        let device = server.await.unwrap();
+        assert_eq!(device.partitions["boot_a"], data);
+        assert_eq!(device.reboot, Some(Some("bootloader".to_string())));
+    }
+}
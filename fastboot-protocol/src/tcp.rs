@@ -0,0 +1,526 @@
+use std::io;
+
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tracing::trace;
+
+use crate::client::FastBoot;
+use crate::transport::{FastbootTransport, DEFAULT_DATA_BUFFER_SIZE};
+
+/// Default TCP port used by fastboot
+pub const DEFAULT_PORT: u16 = 5554;
+
+/// Version of the fastboot TCP protocol implemented
+const PROTOCOL_VERSION: u32 = 1;
+
+/// Maximum size of a command or response packet; Commands are limited to 4096 bytes and responses
+/// to 256 bytes by the fastboot protocol
+const MAX_PACKET_SIZE: u64 = 4096;
+
+/// Errors when connecting to a fastboot device over TCP
+#[derive(Debug, Error)]
+pub enum TcpFastBootOpenError {
+    #[error("Failed to connect: {0}")]
+    Connect(std::io::Error),
+    #[error("Handshake failed: {0}")]
+    Handshake(std::io::Error),
+    #[error("Invalid handshake reply: {0:?}")]
+    InvalidHandshake([u8; 4]),
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(u32),
+}
+
+/// Parse a `tcp:host[:port]` fastboot target into the host and port
+///
+/// The `tcp:` prefix is optional. When no port is given [DEFAULT_PORT] is used; IPv6 addresses
+/// need to be enclosed in brackets when followed by a port, e.g. `tcp:[::1]:5554`
+pub fn parse_target(target: &str) -> Option<(&str, u16)> {
+    let target = target.strip_prefix("tcp:").unwrap_or(target);
+    let (host, port) = match target.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().ok()?)
+        }
+        _ => (target, DEFAULT_PORT),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        None
+    } else {
+        Some((host, port))
+    }
+}
+
+/// Fastboot over TCP transport
+///
+/// After a `FB01` handshake every packet is prefixed with its length as a 8 byte big endian
+/// number
+///
+/// Reads and writes keep track of their progress, so cancelling them doesn't break the framing;
+/// A partially received packet is completed by the next read. Only when a packet was partially
+/// sent when transfers got cancelled the framing can't be recovered, after which all operations
+/// fail.
+pub struct TcpTransport {
+    stream: TcpStream,
+    // Length of the next packet being received
+    header: [u8; 8],
+    header_len: usize,
+    // Command or response packet being received and the amount received so far
+    packet: Option<(Vec<u8>, usize)>,
+    // Data left in the packet currently being read by read_data
+    data_left: u64,
+    // Whether the rest of the current data packet may be skipped after transfers got cancelled
+    skip_data: bool,
+    // Packet being sent and the amount sent so far
+    tx: Vec<u8>,
+    tx_sent: usize,
+    broken: bool,
+}
+
+impl TcpTransport {
+    /// Connect to a fastboot device listening on the given address
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, TcpFastBootOpenError> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .map_err(TcpFastBootOpenError::Connect)?;
+        Self::from_stream(stream).await
+    }
+
+    /// Connect to a fastboot `tcp:host[:port]` target; See [parse_target]
+    pub async fn connect_target(target: &str) -> Result<Self, TcpFastBootOpenError> {
+        let (host, port) = parse_target(target).ok_or_else(|| {
+            TcpFastBootOpenError::Connect(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid tcp target: {target}"),
+            ))
+        })?;
+        Self::connect((host, port)).await
+    }
+
+    /// Create a fastboot transport from a connected TCP stream, doing the protocol handshake
+    #[tracing::instrument(skip_all, err)]
+    pub async fn from_stream(mut stream: TcpStream) -> Result<Self, TcpFastBootOpenError> {
+        // Packets are small and latency sensitive
+        stream
+            .set_nodelay(true)
+            .map_err(TcpFastBootOpenError::Connect)?;
+
+        stream
+            .write_all(format!("FB{PROTOCOL_VERSION:02}").as_bytes())
+            .await
+            .map_err(TcpFastBootOpenError::Handshake)?;
+        Self::read_handshake(&mut stream).await?;
+
+        Ok(Self::new(stream))
+    }
+
+    /// Create a device side fastboot transport from a TCP stream accepted from a host, doing the
+    /// protocol handshake
+    ///
+    /// This is meant to be used with [crate::server::FastbootServer]
+    #[tracing::instrument(skip_all, err)]
+    pub async fn accept_stream(mut stream: TcpStream) -> Result<Self, TcpFastBootOpenError> {
+        stream
+            .set_nodelay(true)
+            .map_err(TcpFastBootOpenError::Connect)?;
+
+        Self::read_handshake(&mut stream).await?;
+        stream
+            .write_all(format!("FB{PROTOCOL_VERSION:02}").as_bytes())
+            .await
+            .map_err(TcpFastBootOpenError::Handshake)?;
+
+        Ok(Self::new(stream))
+    }
+
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            header: [0; 8],
+            header_len: 0,
+            packet: None,
+            data_left: 0,
+            skip_data: false,
+            tx: vec![],
+            tx_sent: 0,
+            broken: false,
+        }
+    }
+
+    async fn read_handshake(stream: &mut TcpStream) -> Result<u32, TcpFastBootOpenError> {
+        let mut handshake = [0; 4];
+        stream
+            .read_exact(&mut handshake)
+            .await
+            .map_err(TcpFastBootOpenError::Handshake)?;
+        // The version is exactly two decimal digits; u32 parsing alone would also accept signs
+        let version = handshake
+            .strip_prefix(b"FB")
+            .filter(|v| v.iter().all(u8::is_ascii_digit))
+            .and_then(|v| std::str::from_utf8(v).ok())
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or(TcpFastBootOpenError::InvalidHandshake(handshake))?;
+        trace!("Fastboot TCP protocol version: {version}");
+        if version < PROTOCOL_VERSION {
+            return Err(TcpFastBootOpenError::UnsupportedVersion(version));
+        }
+        Ok(version)
+    }
+
+    fn check_broken(&self) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Packet partially sent before transfers got cancelled",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Read the length of the next packet
+    async fn read_header(&mut self) -> io::Result<u64> {
+        while self.header_len < self.header.len() {
+            self.header_len +=
+                read_some(&mut self.stream, &mut self.header[self.header_len..]).await?;
+        }
+        self.header_len = 0;
+        Ok(u64::from_be_bytes(self.header))
+    }
+
+    /// Skip the rest of the current data packet
+    async fn skip_data(&mut self) -> io::Result<()> {
+        let mut buf = vec![0; self.data_left.min(DEFAULT_DATA_BUFFER_SIZE as u64) as usize];
+        while self.data_left > 0 {
+            let len = self.data_left.min(buf.len() as u64) as usize;
+            self.data_left -= read_some(&mut self.stream, &mut buf[..len]).await? as u64;
+        }
+        self.skip_data = false;
+        Ok(())
+    }
+
+    /// Send the remainder of the packet being sent
+    async fn flush_tx(&mut self) -> io::Result<()> {
+        while self.tx_sent < self.tx.len() {
+            let n = self.stream.write(&self.tx[self.tx_sent..]).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.tx_sent += n;
+        }
+        Ok(())
+    }
+}
+
+/// Read whatever is available, treating the end of the stream as an error
+async fn read_some(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    match stream.read(buf).await? {
+        0 => Err(io::ErrorKind::UnexpectedEof.into()),
+        n => Ok(n),
+    }
+}
+
+impl FastbootTransport for TcpTransport {
+    async fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.check_broken()?;
+        // Finish a packet of which sending got interrupted
+        self.flush_tx().await?;
+        self.tx.clear();
+        self.tx
+            .extend_from_slice(&(packet.len() as u64).to_be_bytes());
+        self.tx.extend_from_slice(packet);
+        self.tx_sent = 0;
+        self.flush_tx().await
+    }
+
+    async fn receive_packet(&mut self) -> io::Result<Vec<u8>> {
+        self.check_broken()?;
+        if self.data_left > 0 {
+            if !self.skip_data {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Packet received while data is still pending",
+                ));
+            }
+            self.skip_data().await?;
+        }
+        if self.packet.is_none() {
+            let len = self.read_header().await?;
+            if len > MAX_PACKET_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Packet too big: {len} bytes"),
+                ));
+            }
+            self.packet = Some((vec![0; len as usize], 0));
+        }
+        if let Some((packet, received)) = &mut self.packet {
+            while *received < packet.len() {
+                *received += read_some(&mut self.stream, &mut packet[*received..]).await?;
+            }
+        }
+        Ok(self
+            .packet
+            .take()
+            .map(|(packet, _)| packet)
+            .unwrap_or_default())
+    }
+
+    async fn read_data(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        self.check_broken()?;
+        // Data packets can be arbitrarily big, so read those in bounded chunks
+        while self.data_left == 0 {
+            self.data_left = self.read_header().await?;
+            self.skip_data = false;
+        }
+        let max = max.min(self.data_buffer_size()) as u64;
+        let mut data = vec![0; self.data_left.min(max) as usize];
+        let n = read_some(&mut self.stream, &mut data).await?;
+        data.truncate(n);
+        self.data_left -= n as u64;
+        Ok(data)
+    }
+
+    fn cancel_transfers(&mut self) {
+        // Partially received packets are completed by the next read, but the rest of a data
+        // packet is no longer of interest
+        self.skip_data = true;
+        if self.tx_sent == 0 {
+            self.tx.clear();
+        } else if self.tx_sent < self.tx.len() {
+            self.broken = true;
+        }
+    }
+}
+
+/// TCP fastboot client
+pub type TcpFastBoot = FastBoot<TcpTransport>;
+
+impl FastBoot<TcpTransport> {
+    /// Connect to a fastboot device listening on the given address
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, TcpFastBootOpenError> {
+        TcpTransport::connect(addr).await.map(Self::new)
+    }
+
+    /// Connect to a fastboot `tcp:host[:port]` target; See [parse_target]
+    pub async fn connect_target(target: &str) -> Result<Self, TcpFastBootOpenError> {
+        TcpTransport::connect_target(target).await.map(Self::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+
+    async fn read_packet(stream: &mut TcpStream) -> Vec<u8> {
+        let len = stream.read_u64().await.unwrap();
+        let mut packet = vec![0; len as usize];
+        stream.read_exact(&mut packet).await.unwrap();
+        packet
+    }
+
+    async fn write_packet(stream: &mut TcpStream, packet: &[u8]) {
+        stream.write_u64(packet.len() as u64).await.unwrap();
+        stream.write_all(packet).await.unwrap();
+    }
+
+    /// Minimal stand-in for a fastboot device listening on TCP
+    async fn serve(listener: TcpListener) -> Vec<u8> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 4];
+        stream.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(&handshake, b"FB01");
+        stream.write_all(b"FB01").await.unwrap();
+
+        let mut downloaded = vec![];
+        loop {
+            let cmd = read_packet(&mut stream).await;
+            let cmd = String::from_utf8(cmd).unwrap();
+            if cmd == "getvar:version" {
+                write_packet(&mut stream, b"INFOsome info").await;
+                write_packet(&mut stream, b"OKAY0.4").await;
+            } else if let Some(size) = cmd.strip_prefix("download:") {
+                let size = usize::from_str_radix(size, 16).unwrap();
+                write_packet(&mut stream, format!("DATA{size:08x}").as_bytes()).await;
+                // Data may be split over multiple packets
+                while downloaded.len() < size {
+                    downloaded.extend(read_packet(&mut stream).await);
+                }
+                write_packet(&mut stream, b"OKAY").await;
+            } else if cmd == "upload" {
+                let data = vec![0x55; 3 * 1024 * 1024];
+                write_packet(&mut stream, format!("DATA{:08x}", data.len()).as_bytes()).await;
+                write_packet(&mut stream, &data).await;
+                write_packet(&mut stream, b"OKAY").await;
+            } else if cmd == "getvar:slow" {
+                // Pause halfway through the length, so the client times out while receiving it
+                let response = b"OKAYslow";
+                let len = (response.len() as u64).to_be_bytes();
+                stream.write_all(&len[..4]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                stream.write_all(&len[4..]).await.unwrap();
+                stream.write_all(response).await.unwrap();
+            } else if cmd == "flash:boot" {
+                write_packet(&mut stream, b"OKAY").await;
+            } else if cmd == "reboot" {
+                write_packet(&mut stream, b"OKAY").await;
+                return downloaded;
+            } else {
+                write_packet(&mut stream, b"FAILunknown command").await;
+            }
+        }
+    }
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(parse_target("tcp:localhost"), Some(("localhost", 5554)));
+        assert_eq!(parse_target("tcp:10.0.0.2:1234"), Some(("10.0.0.2", 1234)));
+        assert_eq!(parse_target("10.0.0.2:1234"), Some(("10.0.0.2", 1234)));
+        assert_eq!(parse_target("tcp:[::1]:1234"), Some(("::1", 1234)));
+        assert_eq!(parse_target("tcp:::1"), Some(("::1", 5554)));
+        assert_eq!(parse_target("tcp:host:port"), None);
+        assert_eq!(parse_target("tcp:"), None);
+    }
+
+    #[tokio::test]
+    async fn getvar_download_flash() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener));
+
+        let mut fb = TcpFastBoot::connect(addr).await.unwrap();
+        assert_eq!(fb.get_var("version").await.unwrap(), "0.4");
+
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        let mut download = fb.download(data.len() as u64).await.unwrap();
+        download.extend_from_slice(&data).await.unwrap();
+        download.finish().await.unwrap();
+        fb.flash("boot").await.unwrap();
+
+        let err = fb.erase("boot").await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::client::FastBootError::FastbootFailed(_)
+        ));
+        fb.reboot().await.unwrap();
+
+        assert_eq!(server.await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn resync_after_cancel() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener));
+
+        let mut fb = TcpFastBoot::connect(addr).await.unwrap();
+        fb.set_timeouts(crate::client::Timeouts {
+            command: Some(Duration::from_millis(200)),
+            long_command: None,
+            data: None,
+        });
+
+        // Time out in the middle of receiving a response
+        let err = fb.get_var("slow").await.unwrap_err();
+        assert!(matches!(err, crate::client::FastBootError::Timeout(_)));
+        fb.resync().await.unwrap();
+        assert_eq!(fb.get_var("version").await.unwrap(), "0.4");
+
+        // Abandon an upload after the first chunk of a big data packet
+        let mut upload = fb.upload().await.unwrap();
+        assert!(upload.read().await.unwrap().is_some());
+        drop(upload);
+        fb.resync().await.unwrap();
+        assert_eq!(fb.get_var("version").await.unwrap(), "0.4");
+
+        // Abandon a download halfway
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        let mut download = fb.download(data.len() as u64).await.unwrap();
+        download
+            .extend_from_slice(&data[..data.len() / 2])
+            .await
+            .unwrap();
+        drop(download);
+        fb.resync().await.unwrap();
+        assert_eq!(fb.get_var("version").await.unwrap(), "0.4");
+        fb.reboot().await.unwrap();
+
+        let downloaded = server.await.unwrap();
+        assert_eq!(downloaded.len(), data.len());
+        assert_eq!(&downloaded[..1024 * 1024], &data[..1024 * 1024]);
+    }
+
+    #[tokio::test]
+    async fn packet_too_big() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; 4];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(b"FB01").await.unwrap();
+            read_packet(&mut stream).await;
+            stream.write_u64(u64::MAX).await.unwrap();
+            // Keep the connection open until the client gives up
+            let _ = stream.read_u8().await;
+        });
+
+        let mut fb = TcpFastBoot::connect(addr).await.unwrap();
+        let err = fb.get_var("version").await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::client::FastBootError::Transport(e) if e.kind() == io::ErrorKind::InvalidData
+        ));
+    }
+
+    #[tokio::test]
+    async fn upload_in_chunks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        let sent = data.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut handshake = [0; 4];
+            stream.read_exact(&mut handshake).await.unwrap();
+            stream.write_all(b"FB01").await.unwrap();
+            assert_eq!(read_packet(&mut stream).await, b"upload");
+            write_packet(&mut stream, format!("DATA{:08x}", sent.len()).as_bytes()).await;
+            // All data in a single packet
+            write_packet(&mut stream, &sent).await;
+            write_packet(&mut stream, b"OKAY").await;
+        });
+
+        let mut fb = TcpFastBoot::connect(addr).await.unwrap();
+        let mut upload = fb.upload().await.unwrap();
+        let mut received = vec![];
+        while let Some(chunk) = upload.read().await.unwrap() {
+            assert!(chunk.len() <= crate::transport::DEFAULT_DATA_BUFFER_SIZE);
+            received.extend(chunk);
+        }
+        upload.finish().await.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn invalid_handshake() {
+        for reply in [b"NOPE", b"FB+1", b"FB 1"] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut handshake = [0; 4];
+                stream.read_exact(&mut handshake).await.unwrap();
+                stream.write_all(reply).await.unwrap();
+            });
+
+            let err = TcpTransport::connect(addr).await.err().unwrap();
+            assert!(matches!(err, TcpFastBootOpenError::InvalidHandshake(r) if &r == reply));
+        }
+    }
+}
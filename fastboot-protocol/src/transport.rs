@@ -0,0 +1,62 @@
+use std::{future::Future, io};
+
+/// Default size of the buffers used to stream data to the device
+pub const DEFAULT_DATA_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Transport used to exchange fastboot packets with a device
+///
+/// Fastboot is a command/response protocol; Commands and responses are exchanged as individual
+/// packets while the payload of a DATA phase is streamed using [FastbootTransport::write_data].
+/// Implementations only have to take care of framing, the command/response state machine is
+/// handled by [crate::client::FastBoot].
+pub trait FastbootTransport: Send {
+    /// Send a single packet (e.g. a command) to the device
+    fn send_packet(&mut self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Receive a single packet (e.g. a response) from the device
+    fn receive_packet(&mut self) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+
+    /// Preferred size of the buffers passed to [FastbootTransport::write_data]
+    ///
+    /// Buffers will be exactly this size, except for the last buffer of a DATA phase
+    fn data_buffer_size(&self) -> usize {
+        DEFAULT_DATA_BUFFER_SIZE
+    }
+
+    /// Stream a buffer of a DATA phase to the device
+    ///
+    /// Implementations may return before the data has actually been sent;
+    /// [FastbootTransport::flush_data] is used to wait for all data to be sent.
+    fn write_data(&mut self, data: Vec<u8>) -> impl Future<Output = io::Result<()>> + Send {
+        async move { self.send_packet(&data).await }
+    }
+
+    /// Wait for all data passed to [FastbootTransport::write_data] to be sent
+    fn flush_data(&mut self) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Amount of data passed to [FastbootTransport::write_data] that hasn't been sent yet
+    ///
+    /// Used to report the data acknowledged by the device; Implementations returning from
+    /// [FastbootTransport::write_data] before the data was sent should track completed transfers
+    fn pending_data(&self) -> usize {
+        0
+    }
+
+    /// Receive data of a DATA phase from the peer
+    ///
+    /// `max` is the amount of data still expected, implementations should not return more than
+    /// that.
+    fn read_data(&mut self, max: usize) -> impl Future<Output = io::Result<Vec<u8>>> + Send {
+        let _ = max;
+        async move { self.receive_packet().await }
+    }
+
+    /// Cancel all in-flight transfers
+    ///
+    /// Called when an operation timed out or was abandoned, potentially from a `Drop`
+    /// implementation so this can't wait for the cancellation to finish. Implementations have to
+    /// ensure cancelled transfers don't affect later operations.
+    fn cancel_transfers(&mut self) {}
+}
@@ -0,0 +1,512 @@
+use std::{
+    collections::VecDeque,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::net::{lookup_host, ToSocketAddrs, UdpSocket};
+use tracing::{trace, warn};
+
+use crate::client::FastBoot;
+use crate::transport::FastbootTransport;
+
+/// Default UDP port used by fastboot
+pub const DEFAULT_PORT: u16 = 5554;
+
+const HEADER_SIZE: usize = 4;
+
+const ID_ERROR: u8 = 0x00;
+const ID_DEVICE_QUERY: u8 = 0x01;
+const ID_INITIALIZATION: u8 = 0x02;
+const ID_FASTBOOT: u8 = 0x03;
+
+const FLAG_CONTINUATION: u8 = 0x01;
+
+const PROTOCOL_VERSION: u16 = 1;
+const HOST_MAX_PACKET_SIZE: u16 = 8192;
+const MIN_PACKET_SIZE: u16 = 512;
+
+const CONNECT_ATTEMPTS: u32 = 4;
+const DEFAULT_ATTEMPTS: u32 = 10;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Fastboot over UDP errors
+#[derive(Debug, Error)]
+pub enum UdpFastBootError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("No response from device after {0} attempts")]
+    Timeout(u32),
+    #[error("Device reported error: {0}")]
+    Device(String),
+    #[error("Invalid response from device")]
+    InvalidResponse,
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Unsupported maximum packet size: {0}")]
+    UnsupportedPacketSize(u16),
+}
+
+impl From<UdpFastBootError> for io::Error {
+    fn from(e: UdpFastBootError) -> Self {
+        match e {
+            UdpFastBootError::Io(e) => e,
+            UdpFastBootError::Timeout(_) => io::Error::new(io::ErrorKind::TimedOut, e),
+            UdpFastBootError::InvalidResponse => io::Error::new(io::ErrorKind::InvalidData, e),
+            e => io::Error::other(e),
+        }
+    }
+}
+
+/// Fastboot over UDP transport
+///
+/// Every packet sent to the device carries a 4 byte header (id, flags and sequence number) and is
+/// acknowledged by the device with a packet using the same sequence number; When no
+/// acknowledgement is received in time the packet is retransmitted. Packets bigger than the
+/// negotiated maximum packet size are fragmented using the continuation flag. As the device can
+/// only send data in response to a host packet, responses are polled for by sending empty packets.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    sequence: u16,
+    max_packet_size: usize,
+    timeout: Duration,
+    attempts: u32,
+    pending: VecDeque<Vec<u8>>,
+    // Rest of a received data packet that was bigger than requested
+    data: Vec<u8>,
+    // Id of a fragmented packet of which sending got interrupted
+    unfinished: Option<u8>,
+}
+
+impl UdpTransport {
+    /// Connect to a fastboot device listening on the given address
+    #[tracing::instrument(skip_all, err)]
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, UdpFastBootError> {
+        let mut last_err = None;
+        for addr in lookup_host(addr).await? {
+            let local: SocketAddr = match addr {
+                SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(local).await?;
+            match socket.connect(addr).await {
+                Ok(_) => return Self::from_socket(socket).await,
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to")
+            })
+            .into())
+    }
+
+    /// Create a fastboot transport from a UDP socket connected to the device, doing the protocol
+    /// initialization
+    #[tracing::instrument(skip_all, err)]
+    pub async fn from_socket(socket: UdpSocket) -> Result<Self, UdpFastBootError> {
+        let mut transport = Self {
+            socket,
+            sequence: 0,
+            max_packet_size: MIN_PACKET_SIZE.into(),
+            timeout: DEFAULT_TIMEOUT,
+            attempts: DEFAULT_ATTEMPTS,
+            pending: VecDeque::new(),
+            data: vec![],
+            unfinished: None,
+        };
+
+        // Query the sequence number the device expects next, only doing a few attempts so an
+        // unavailable device is detected quickly
+        let reply = transport
+            .send_fragmented(ID_DEVICE_QUERY, &[], CONNECT_ATTEMPTS)
+            .await?;
+        transport.sequence = read_u16(&reply, 0)?;
+
+        let mut init = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        init.extend_from_slice(&HOST_MAX_PACKET_SIZE.to_be_bytes());
+        let reply = transport
+            .send_fragmented(ID_INITIALIZATION, &init, transport.attempts)
+            .await?;
+        let version = read_u16(&reply, 0)?;
+        let packet_size = read_u16(&reply, 2)?;
+        trace!("Fastboot UDP protocol version: {version}, max packet size: {packet_size}");
+        if version < PROTOCOL_VERSION {
+            return Err(UdpFastBootError::UnsupportedVersion(version));
+        }
+        if packet_size < MIN_PACKET_SIZE {
+            return Err(UdpFastBootError::UnsupportedPacketSize(packet_size));
+        }
+        transport.max_packet_size = packet_size.min(HOST_MAX_PACKET_SIZE).into();
+
+        Ok(transport)
+    }
+
+    /// Set the time to wait for a reply before retransmitting a packet
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set the number of times a packet is transmitted before giving up
+    pub fn set_attempts(&mut self, attempts: u32) {
+        self.attempts = attempts.max(1);
+    }
+
+    /// Negotiated maximum packet size, including the header
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Send a single packet and wait for the matching reply, returning the reply flags and data
+    async fn exchange(
+        &mut self,
+        id: u8,
+        flags: u8,
+        data: &[u8],
+        attempts: u32,
+    ) -> Result<(u8, Vec<u8>), UdpFastBootError> {
+        // The sequence number is advanced before waiting for the reply, so when this gets
+        // cancelled the device's reply to this packet can't be mistaken for the reply to the next
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let mut packet = Vec::with_capacity(HEADER_SIZE + data.len());
+        packet.extend_from_slice(&[id, flags]);
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(data);
+
+        let mut buf = vec![0; HOST_MAX_PACKET_SIZE.into()];
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                trace!("Retransmitting packet {sequence}");
+            }
+            self.socket.send(&packet).await?;
+            loop {
+                let Ok(r) = tokio::time::timeout(self.timeout, self.socket.recv(&mut buf)).await
+                else {
+                    break;
+                };
+                let len = r?;
+                if len < HEADER_SIZE {
+                    warn!("Ignoring short packet");
+                    continue;
+                }
+                if buf[2..4] != sequence.to_be_bytes() {
+                    // Stale reply to an earlier retransmission
+                    continue;
+                }
+                let data = &buf[HEADER_SIZE..len];
+                if buf[0] == ID_ERROR {
+                    return Err(UdpFastBootError::Device(
+                        String::from_utf8_lossy(data).into_owned(),
+                    ));
+                }
+                if buf[0] != id {
+                    return Err(UdpFastBootError::InvalidResponse);
+                }
+                return Ok((buf[1], data.to_vec()));
+            }
+        }
+        Err(UdpFastBootError::Timeout(attempts))
+    }
+
+    /// Send data fragmented to the maximum packet size, returning the (reassembled) reply
+    async fn send_fragmented(
+        &mut self,
+        id: u8,
+        data: &[u8],
+        attempts: u32,
+    ) -> Result<Vec<u8>, UdpFastBootError> {
+        // Terminate a packet of which sending got interrupted, so it doesn't get merged with this
+        // one
+        if let Some(id) = self.unfinished.take() {
+            trace!("Terminating interrupted packet");
+            self.exchange(id, 0, &[], attempts).await?;
+        }
+
+        let mut reply = vec![];
+        let mut fragments = data.chunks(self.max_packet_size - HEADER_SIZE).peekable();
+        loop {
+            let fragment = fragments.next().unwrap_or_default();
+            let flags = if fragments.peek().is_some() {
+                FLAG_CONTINUATION
+            } else {
+                0
+            };
+            if flags & FLAG_CONTINUATION != 0 {
+                self.unfinished = Some(id);
+            }
+            let (reply_flags, data) = self.exchange(id, flags, fragment, attempts).await?;
+            if flags & FLAG_CONTINUATION == 0 {
+                self.unfinished = None;
+            }
+            reply.extend_from_slice(&data);
+            // Keep sending (empty packets) until both sides are done
+            if flags & FLAG_CONTINUATION == 0 && reply_flags & FLAG_CONTINUATION == 0 {
+                return Ok(reply);
+            }
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, UdpFastBootError> {
+    data.get(offset..offset + 2)
+        .map(|v| u16::from_be_bytes([v[0], v[1]]))
+        .ok_or(UdpFastBootError::InvalidResponse)
+}
+
+impl FastbootTransport for UdpTransport {
+    async fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        let reply = self
+            .send_fragmented(ID_FASTBOOT, packet, self.attempts)
+            .await?;
+        // Devices may respond directly in the acknowledgement
+        if !reply.is_empty() {
+            self.pending.push_back(reply);
+        }
+        Ok(())
+    }
+
+    async fn receive_packet(&mut self) -> io::Result<Vec<u8>> {
+        if !self.data.is_empty() {
+            trace!("Discarding {} bytes of unread data", self.data.len());
+            self.data.clear();
+        }
+        if let Some(packet) = self.pending.pop_front() {
+            return Ok(packet);
+        }
+        loop {
+            let reply = self
+                .send_fragmented(ID_FASTBOOT, &[], self.attempts)
+                .await?;
+            if !reply.is_empty() {
+                return Ok(reply);
+            }
+        }
+    }
+
+    async fn read_data(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        // Keep the excess of a packet bigger than requested for the next read
+        if self.data.is_empty() {
+            self.data = self.receive_packet().await?;
+        }
+        let rest = self.data.split_off(max.min(self.data.len()));
+        Ok(std::mem::replace(&mut self.data, rest))
+    }
+}
+
+/// UDP fastboot client
+pub type UdpFastBoot = FastBoot<UdpTransport>;
+
+impl FastBoot<UdpTransport> {
+    /// Connect to a fastboot device listening on the given address
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, UdpFastBootError> {
+        UdpTransport::connect(addr).await.map(Self::new)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DEVICE_PACKET_SIZE: usize = 512;
+    const LONG_VALUE: [u8; 600] = [b'x'; 600];
+
+    fn reply(header: &[u8], flags: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![header[0], flags, header[2], header[3]];
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// Minimal stand-in for a fastboot device speaking UDP; Drops the first fastboot command to
+    /// exercise retransmissions and returns all downloaded data
+    async fn serve(socket: UdpSocket) -> Vec<u8> {
+        let mut last: Option<(Vec<u8>, Vec<u8>)> = None;
+        let mut dropped = false;
+        let mut mute = false;
+        let mut command = vec![];
+        let mut responses: VecDeque<Vec<u8>> = VecDeque::new();
+        let mut download_left = 0;
+        let mut downloaded = vec![];
+        let mut buf = [0; 8192];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let packet = &buf[..len];
+            assert!(len <= DEVICE_PACKET_SIZE);
+            if let Some((header, reply)) = &last {
+                if header[2..4] == packet[2..4] {
+                    if !mute {
+                        socket.send_to(reply, peer).await.unwrap();
+                    }
+                    continue;
+                }
+            }
+            mute = false;
+
+            let data = &packet[HEADER_SIZE..];
+            let reply = match packet[0] {
+                ID_DEVICE_QUERY => reply(packet, 0, &[0x12, 0x34]),
+                ID_INITIALIZATION => {
+                    assert_eq!(packet[2..4], [0x12, 0x34]);
+                    let mut r = PROTOCOL_VERSION.to_be_bytes().to_vec();
+                    r.extend_from_slice(&(DEVICE_PACKET_SIZE as u16).to_be_bytes());
+                    reply(packet, 0, &r)
+                }
+                ID_FASTBOOT if data.is_empty() => match responses.pop_front() {
+                    Some(r) if r.len() > DEVICE_PACKET_SIZE - HEADER_SIZE => {
+                        let (first, rest) = r.split_at(DEVICE_PACKET_SIZE - HEADER_SIZE);
+                        responses.push_front(rest.to_vec());
+                        reply(packet, FLAG_CONTINUATION, first)
+                    }
+                    Some(r) => reply(packet, 0, &r),
+                    None => reply(packet, 0, &[]),
+                },
+                ID_FASTBOOT if download_left > 0 => {
+                    downloaded.extend_from_slice(data);
+                    download_left -= data.len();
+                    if download_left == 0 {
+                        responses.push_back(b"OKAY".to_vec());
+                    }
+                    reply(packet, 0, &[])
+                }
+                ID_FASTBOOT => {
+                    if !dropped {
+                        dropped = true;
+                        continue;
+                    }
+                    command.extend_from_slice(data);
+                    if packet[1] & FLAG_CONTINUATION == 0 {
+                        let cmd = String::from_utf8(std::mem::take(&mut command)).unwrap();
+                        if cmd == "getvar:long" {
+                            let mut r = b"OKAY".to_vec();
+                            r.extend_from_slice(&LONG_VALUE);
+                            responses.push_back(r);
+                        } else if cmd == "getvar:lost" {
+                            // Acknowledgements get lost, so the client gives up on the command
+                            mute = true;
+                            responses.push_back(b"OKAYlost".to_vec());
+                        } else if cmd == "upload" {
+                            let size = LONG_VALUE.len();
+                            responses.push_back(format!("DATA{size:08x}").into_bytes());
+                            responses.push_back(LONG_VALUE.to_vec());
+                            responses.push_back(b"OKAY".to_vec());
+                        } else if let Some(size) = cmd.strip_prefix("download:") {
+                            download_left = usize::from_str_radix(size, 16).unwrap();
+                            responses.push_back(format!("DATA{size}").into_bytes());
+                        } else if cmd == "reboot" {
+                            socket
+                                .send_to(&reply(packet, 0, b"OKAY"), peer)
+                                .await
+                                .unwrap();
+                            return downloaded;
+                        } else {
+                            responses.push_back(b"FAILunknown command".to_vec());
+                        }
+                    }
+                    reply(packet, 0, &[])
+                }
+                _ => reply(&[ID_ERROR, 0, packet[2], packet[3]], 0, b"Unknown packet"),
+            };
+            if !mute {
+                socket.send_to(&reply, peer).await.unwrap();
+            }
+            last = Some((packet.to_vec(), reply));
+        }
+    }
+
+    #[tokio::test]
+    async fn getvar_download() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(serve(socket));
+
+        let mut transport = UdpTransport::connect(addr).await.unwrap();
+        assert_eq!(transport.max_packet_size(), DEVICE_PACKET_SIZE);
+        transport.set_timeout(Duration::from_millis(50));
+        let mut fb = FastBoot::new(transport);
+
+        let value = fb.get_var("long").await.unwrap();
+        assert_eq!(value.as_bytes(), LONG_VALUE);
+
+        let data: Vec<u8> = (0..4000).map(|i| i as u8).collect();
+        let mut download = fb.download(data.len() as u64).await.unwrap();
+        download.extend_from_slice(&data).await.unwrap();
+        download.finish().await.unwrap();
+
+        let err = fb.flash("boot").await.unwrap_err();
+        assert!(matches!(
+            err,
+            crate::client::FastBootError::FastbootFailed(_)
+        ));
+        fb.reboot().await.unwrap();
+
+        assert_eq!(server.await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn command_timed_out() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(serve(socket));
+
+        let mut transport = UdpTransport::connect(addr).await.unwrap();
+        transport.set_timeout(Duration::from_millis(50));
+        let mut fb = FastBoot::new(transport);
+        fb.set_timeouts(crate::client::Timeouts {
+            command: Some(Duration::from_millis(100)),
+            long_command: None,
+            data: None,
+        });
+
+        let err = fb.get_var("lost").await.unwrap_err();
+        assert!(matches!(err, crate::client::FastBootError::Timeout(_)));
+        fb.resync().await.unwrap();
+
+        // The cached reply to the timed out command must not be taken for this one's
+        let value = fb.get_var("long").await.unwrap();
+        assert_eq!(value.as_bytes(), LONG_VALUE);
+        fb.reboot().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_data_in_chunks() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let server = tokio::spawn(serve(socket));
+
+        let mut transport = UdpTransport::connect(addr).await.unwrap();
+        transport.set_timeout(Duration::from_millis(50));
+        transport.send_packet(b"upload").await.unwrap();
+        assert_eq!(transport.receive_packet().await.unwrap(), b"DATA00000258");
+        let mut received = vec![];
+        while received.len() < LONG_VALUE.len() {
+            let data = transport.read_data(100).await.unwrap();
+            assert!(!data.is_empty() && data.len() <= 100);
+            received.extend(data);
+        }
+        assert_eq!(received, LONG_VALUE);
+        assert_eq!(transport.receive_packet().await.unwrap(), b"OKAY");
+
+        let mut fb = FastBoot::new(transport);
+        fb.reboot().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn no_device() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        let err = tokio::time::timeout(Duration::from_secs(10), UdpTransport::from_socket(socket))
+            .await
+            .unwrap()
+            .err()
+            .unwrap();
+        assert!(matches!(err, UdpFastBootError::Timeout(CONNECT_ATTEMPTS)));
+    }
+}
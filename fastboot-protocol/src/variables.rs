@@ -0,0 +1,268 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::client::{Slot, SnapshotUpdateStatus};
+use crate::protocol::parse_size;
+
+/// Variables that take a partition (or slot) argument, e.g. `partition-size:system_a`
+const PARAMETERIZED_VARS: &[&str] = &[
+    "partition-size",
+    "partition-type",
+    "is-logical",
+    "has-slot",
+    "slot-successful",
+    "slot-unbootable",
+    "slot-retry-count",
+];
+
+/// Split a `getvar:all` INFO line into the variable name and value
+///
+/// Bootloaders use both `name: value` and `name:value`; For variables taking an argument the
+/// argument is kept as part of the name (e.g. `partition-size:system_a`)
+pub(crate) fn parse_var_line(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = match line.split_once(": ") {
+        Some(kv) => kv,
+        None => {
+            let (name, rest) = line.split_once(':')?;
+            match rest.split_once(':') {
+                Some((arg, value)) if PARAMETERIZED_VARS.contains(&name) => {
+                    (&line[..name.len() + 1 + arg.len()], value)
+                }
+                _ => (name, rest),
+            }
+        }
+    };
+    Some((key.trim(), value.trim()))
+}
+
+/// Parse a `yes` or `no` variable value
+pub(crate) fn parse_yes_no(value: &str) -> Option<bool> {
+    match value {
+        "yes" => Some(true),
+        "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether `name` is `base` with a slot suffix, e.g. `system_a` for `system`
+fn is_slot_of(name: &str, base: &str) -> bool {
+    name.strip_prefix(base)
+        .and_then(|suffix| suffix.strip_prefix('_'))
+        .is_some_and(|slot| slot.len() == 1 && slot.chars().all(|c| c.is_ascii_lowercase()))
+}
+
+/// Information about a single partition as reported by the device variables
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartitionInfo {
+    /// Size in bytes (`partition-size`)
+    pub size: Option<u64>,
+    /// Filesystem type, e.g. `raw` or `ext4` (`partition-type`)
+    pub partition_type: Option<String>,
+    /// Whether the partition is a logical partition (`is-logical`)
+    pub is_logical: Option<bool>,
+    /// Whether the partition has A/B slots (`has-slot`)
+    pub has_slot: Option<bool>,
+}
+
+/// Typed view on the variables reported by `getvar:all`
+///
+/// Variables not reported by the device (or not in the expected format) are `None`. All
+/// variables remain available in their raw form using [DeviceVariables::get].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceVariables {
+    /// Product name (`product`)
+    pub product: Option<String>,
+    /// Serial number (`serialno`)
+    pub serialno: Option<String>,
+    /// Whether secure boot is enforced (`secure`)
+    pub secure: Option<bool>,
+    /// Whether the bootloader is unlocked (`unlocked`)
+    pub unlocked: Option<bool>,
+    /// Maximum size of a single download (`max-download-size`)
+    pub max_download_size: Option<u64>,
+    /// Whether the device runs userspace fastboot (`is-userspace`)
+    pub is_userspace: Option<bool>,
+    /// Currently active slot (`current-slot`)
+    pub current_slot: Option<Slot>,
+    /// Number of slots (`slot-count`)
+    pub slot_count: Option<u32>,
+    /// Supported fastboot protocol version (`version`)
+    pub version: Option<String>,
+    /// Bootloader version (`version-bootloader`)
+    pub version_bootloader: Option<String>,
+    /// Baseband version (`version-baseband`)
+    pub version_baseband: Option<String>,
+    /// Status of a virtual A/B snapshot update (`snapshot-update-status`)
+    pub snapshot_update_status: Option<SnapshotUpdateStatus>,
+    partitions: BTreeMap<String, PartitionInfo>,
+    vars: HashMap<String, String>,
+}
+
+impl DeviceVariables {
+    /// Build from raw variable names and values, e.g. as returned by
+    /// [crate::client::FastBoot::get_all_vars]
+    pub fn from_vars(vars: HashMap<String, String>) -> Self {
+        let get = |var: &str| vars.get(var).map(String::as_str);
+        let string = |var: &str| get(var).map(String::from);
+        let yes_no = |var: &str| get(var).and_then(parse_yes_no);
+
+        let mut partitions: BTreeMap<String, PartitionInfo> = BTreeMap::new();
+        let mut has_slot = vec![];
+        for (key, value) in &vars {
+            let Some((name, partition)) = key.split_once(':') else {
+                continue;
+            };
+            if !["partition-size", "partition-type", "is-logical", "has-slot"].contains(&name) {
+                continue;
+            }
+            if name == "has-slot" {
+                has_slot.push((partition, parse_yes_no(value)));
+                continue;
+            }
+            let info = partitions.entry(partition.to_string()).or_default();
+            match name {
+                "partition-size" => info.size = parse_size(value).ok(),
+                "partition-type" => info.partition_type = Some(value.clone()),
+                _ => info.is_logical = parse_yes_no(value),
+            }
+        }
+        // has-slot is reported for the base name while the other variables use the slotted names
+        for (base, value) in has_slot {
+            let mut slotted = partitions
+                .iter_mut()
+                .filter(|(name, _)| is_slot_of(name, base))
+                .peekable();
+            if slotted.peek().is_none() {
+                partitions.entry(base.to_string()).or_default().has_slot = value;
+            } else {
+                slotted.for_each(|(_, info)| info.has_slot = value);
+            }
+        }
+
+        Self {
+            product: string("product"),
+            serialno: string("serialno"),
+            secure: yes_no("secure"),
+            unlocked: yes_no("unlocked"),
+            max_download_size: get("max-download-size").and_then(|v| parse_size(v).ok()),
+            is_userspace: yes_no("is-userspace"),
+            current_slot: get("current-slot").and_then(|v| v.parse().ok()),
+            slot_count: get("slot-count").and_then(|v| v.parse().ok()),
+            version: string("version"),
+            version_bootloader: string("version-bootloader"),
+            version_baseband: string("version-baseband"),
+            snapshot_update_status: get("snapshot-update-status")
+                .and_then(SnapshotUpdateStatus::from_var),
+            partitions,
+            vars,
+        }
+    }
+
+    /// Partitions reported by the device, combining the per partition variables
+    ///
+    /// Slotted partitions are keyed by their slotted name (e.g. `system_a`), with `has-slot` of
+    /// the base name applied to each slot
+    pub fn partitions(&self) -> &BTreeMap<String, PartitionInfo> {
+        &self.partitions
+    }
+
+    /// Raw value of a variable
+    pub fn get(&self, var: &str) -> Option<&str> {
+        self.vars.get(var).map(String::as_str)
+    }
+
+    /// All variables in their raw form
+    pub fn vars(&self) -> &HashMap<String, String> {
+        &self.vars
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn var_lines() {
+        assert_eq!(parse_var_line("version: 0.4"), Some(("version", "0.4")));
+        assert_eq!(parse_var_line("version:0.4"), Some(("version", "0.4")));
+        assert_eq!(
+            parse_var_line("partition-size:boot_a: 0x4000"),
+            Some(("partition-size:boot_a", "0x4000"))
+        );
+        assert_eq!(
+            parse_var_line("partition-size:system_a:0x1000"),
+            Some(("partition-size:system_a", "0x1000"))
+        );
+        assert_eq!(
+            parse_var_line("version-bootloader:u-boot:2024.01"),
+            Some(("version-bootloader", "u-boot:2024.01"))
+        );
+        assert_eq!(parse_var_line("garbage"), None);
+    }
+
+    #[test]
+    fn device_variables() {
+        let vars: HashMap<String, String> = [
+            ("product", "virtual"),
+            ("secure", "no"),
+            ("unlocked", "yes"),
+            ("max-download-size", "0x10000000"),
+            ("is-userspace", "yes"),
+            ("current-slot", "b"),
+            ("slot-count", "2"),
+            ("version-bootloader", "u-boot:2024.01"),
+            ("snapshot-update-status", "merging"),
+            ("partition-size:system_a", "0x0000000040000000"),
+            ("partition-type:system_a", "ext4"),
+            ("is-logical:system_a", "yes"),
+            ("partition-size:system_b", "0x0000000040000000"),
+            ("has-slot:system", "yes"),
+            ("partition-size:boot", "0x4000"),
+            ("has-slot:boot", "no"),
+            ("has-slot:vbmeta", "no"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let vars = DeviceVariables::from_vars(vars);
+        assert_eq!(vars.product.as_deref(), Some("virtual"));
+        assert_eq!(vars.serialno, None);
+        assert_eq!(vars.secure, Some(false));
+        assert_eq!(vars.unlocked, Some(true));
+        assert_eq!(vars.max_download_size, Some(0x10000000));
+        assert_eq!(vars.is_userspace, Some(true));
+        assert_eq!(vars.current_slot, Some(Slot::B));
+        assert_eq!(vars.slot_count, Some(2));
+        assert_eq!(vars.version_bootloader.as_deref(), Some("u-boot:2024.01"));
+        assert_eq!(
+            vars.snapshot_update_status,
+            Some(SnapshotUpdateStatus::Merging)
+        );
+        assert_eq!(vars.get("product"), Some("virtual"));
+
+        let partitions = vars.partitions();
+        assert_eq!(
+            partitions.keys().collect::<Vec<_>>(),
+            ["boot", "system_a", "system_b", "vbmeta"]
+        );
+        assert_eq!(
+            partitions["system_a"],
+            PartitionInfo {
+                size: Some(0x40000000),
+                partition_type: Some("ext4".to_string()),
+                is_logical: Some(true),
+                has_slot: Some(true),
+            }
+        );
+        assert_eq!(partitions["system_b"].has_slot, Some(true));
+        assert_eq!(
+            partitions["boot"],
+            PartitionInfo {
+                size: Some(0x4000),
+                has_slot: Some(false),
+                ..Default::default()
+            }
+        );
+        assert_eq!(partitions["vbmeta"].has_slot, Some(false));
+    }
+}
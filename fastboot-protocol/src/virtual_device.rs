@@ -0,0 +1,654 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use android_sparse_image::{
+    ChunkHeader, ChunkType, FileHeader, ParseError, CHUNK_HEADER_BYTES_LEN, FILE_HEADER_BYTES_LEN,
+};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+
+use crate::server::{FastbootHandler, FastbootServer, FastbootServerError, HandlerResult};
+use crate::transport::FastbootTransport;
+
+/// Default maximum download size of a virtual device
+pub const DEFAULT_MAX_DOWNLOAD_SIZE: u64 = 256 * 1024 * 1024;
+
+const PARTITION_EXTENSION: &str = "img";
+
+/// Stateful virtual fastboot device
+///
+/// Emulates a fastboot device for testing flashing tooling. Partitions are kept as
+/// `<partition>.img` files in a directory, where the file size is the partition size. The device
+/// is a [FastbootHandler], [VirtualDevice::serve] runs it in a [FastbootServer] which a
+/// [crate::client::FastBoot] client can connect to.
+///
+/// Supported commands are `getvar`, `download`, `flash` (both raw and android sparse images),
+/// `erase`, `set_active`, `flashing` and `reboot`. Downloads bigger than the maximum download
+/// size are refused. Partitions with an `_a` suffix turn the device into an A/B device. The device
+/// starts unlocked; Once locked flashing and erasing are refused.
+///
+/// File operations are done synchronously.
+pub struct VirtualDevice {
+    dir: PathBuf,
+    vars: BTreeMap<String, String>,
+    max_download_size: u64,
+    current_slot: String,
+    locked: bool,
+    download: Vec<u8>,
+    reboots: Vec<String>,
+}
+
+impl VirtualDevice {
+    /// Create a virtual device keeping its partitions in `dir`
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        let vars = [("version", "0.4"), ("product", "virtual")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Self {
+            dir: dir.into(),
+            vars,
+            max_download_size: DEFAULT_MAX_DOWNLOAD_SIZE,
+            current_slot: "a".to_string(),
+            locked: false,
+            download: vec![],
+            reboots: vec![],
+        }
+    }
+
+    /// Serve the device over an in-memory transport
+    ///
+    /// Returns the host side of the transport and the server, which has to be polled for the
+    /// device to respond. The server finishes with the device once the host side is dropped or a
+    /// reboot has been acknowledged.
+    pub fn serve(
+        self,
+    ) -> (
+        LoopbackTransport,
+        impl Future<Output = Result<Self, FastbootServerError>> + Send,
+    ) {
+        let (host, device) = LoopbackTransport::pair();
+        let mut server = FastbootServer::new(device, self);
+        let run = async move {
+            server.run().await?;
+            Ok(server.into_parts().1)
+        };
+        (host, run)
+    }
+
+    /// Set the maximum download size the device accepts
+    pub fn set_max_download_size(&mut self, size: u64) {
+        self.max_download_size = size;
+    }
+
+    /// Set a variable reported by the device
+    pub fn set_var(&mut self, var: &str, value: &str) {
+        self.vars.insert(var.to_string(), value.to_string());
+    }
+
+    /// Directory containing the partition files
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the file backing a partition
+    pub fn partition_path(&self, partition: &str) -> PathBuf {
+        self.dir.join(partition).with_extension(PARTITION_EXTENSION)
+    }
+
+    /// Create a zero-filled partition of the given size
+    pub fn create_partition(&self, partition: &str, size: u64) -> io::Result<()> {
+        let file = File::create(self.partition_path(partition))?;
+        file.set_len(size)
+    }
+
+    /// Currently active slot
+    pub fn current_slot(&self) -> &str {
+        &self.current_slot
+    }
+
+    /// Whether the bootloader is locked
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Lock or unlock the bootloader
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+    }
+
+    /// All reboot commands received by the device
+    pub fn reboots(&self) -> &[String] {
+        &self.reboots
+    }
+
+    fn partitions(&self) -> io::Result<Vec<(String, u64)>> {
+        let mut partitions = vec![];
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == PARTITION_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|n| n.to_str()) {
+                    partitions.push((name.to_string(), path.metadata()?.len()));
+                }
+            }
+        }
+        partitions.sort();
+        Ok(partitions)
+    }
+
+    fn has_slots(&self) -> io::Result<bool> {
+        Ok(self
+            .partitions()?
+            .iter()
+            .any(|(name, _)| name.ends_with("_a")))
+    }
+
+    fn checked_partition_path(&self, partition: &str) -> Result<PathBuf, String> {
+        if partition.is_empty() || partition.contains(['/', '\\', '.']) {
+            return Err(format!("Invalid partition name: {partition}"));
+        }
+        Ok(self.partition_path(partition))
+    }
+
+    fn open_partition(&self, partition: &str) -> Result<File, String> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.checked_partition_path(partition)?)
+            .map_err(|_| format!("No such partition: {partition}"))
+    }
+
+    fn partition_size(&self, partition: &str) -> Result<u64, String> {
+        std::fs::metadata(self.checked_partition_path(partition)?)
+            .map(|m| m.len())
+            .map_err(|_| format!("No such partition: {partition}"))
+    }
+
+    fn variable(&self, var: &str) -> HandlerResult<String> {
+        let io_err = |e: io::Error| e.to_string();
+        if let Some(partition) = var.strip_prefix("partition-size:") {
+            let size = self.partition_size(partition)?;
+            return Ok(format!("0x{size:016x}"));
+        }
+        if let Some(partition) = var.strip_prefix("partition-type:") {
+            self.partition_size(partition)?;
+            return Ok("raw".to_string());
+        }
+        if let Some(partition) = var.strip_prefix("has-slot:") {
+            let slotted = self
+                .checked_partition_path(&format!("{partition}_a"))?
+                .exists();
+            return Ok(if slotted { "yes" } else { "no" }.to_string());
+        }
+        match var {
+            "max-download-size" => Ok(format!("0x{:08x}", self.max_download_size)),
+            "slot-count" => Ok(if self.has_slots().map_err(io_err)? {
+                "2"
+            } else {
+                "0"
+            }
+            .to_string()),
+            "current-slot" if self.has_slots().map_err(io_err)? => Ok(self.current_slot.clone()),
+            "unlocked" => Ok(if self.locked { "no" } else { "yes" }.to_string()),
+            "secure" => Ok(if self.locked { "yes" } else { "no" }.to_string()),
+            _ => self
+                .vars
+                .get(var)
+                .cloned()
+                .ok_or_else(|| format!("Unknown variable: {var}")),
+        }
+    }
+
+    fn set_lock(&mut self, locked: bool) -> HandlerResult<String> {
+        if self.locked == locked {
+            let state = if locked { "locked" } else { "unlocked" };
+            return Err(format!("Device already {state}"));
+        }
+        self.locked = locked;
+        Ok(String::new())
+    }
+}
+
+impl FastbootHandler for VirtualDevice {
+    async fn get_var(&mut self, var: &str) -> HandlerResult<String> {
+        self.variable(var)
+    }
+
+    async fn get_all_vars(&mut self) -> HandlerResult<Vec<(String, String)>> {
+        let mut vars: Vec<String> = [
+            "max-download-size",
+            "slot-count",
+            "current-slot",
+            "unlocked",
+            "secure",
+        ]
+        .into_iter()
+        .chain(self.vars.keys().map(String::as_str))
+        .map(String::from)
+        .collect();
+        for (partition, _) in self.partitions().map_err(|e| e.to_string())? {
+            vars.push(format!("partition-size:{partition}"));
+            vars.push(format!("partition-type:{partition}"));
+        }
+
+        Ok(vars
+            .into_iter()
+            .filter_map(|var| self.variable(&var).ok().map(|value| (var, value)))
+            .collect())
+    }
+
+    async fn download(&mut self, size: u64) -> HandlerResult<()> {
+        if size > self.max_download_size {
+            return Err("Requested download size is more than max allowed".to_string());
+        }
+        self.download.clear();
+        Ok(())
+    }
+
+    async fn download_data(&mut self, data: &[u8]) -> HandlerResult<()> {
+        self.download.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn flash(&mut self, partition: &str) -> HandlerResult<()> {
+        if self.locked {
+            return Err("Flashing is not allowed in locked state".to_string());
+        }
+        if self.download.is_empty() {
+            return Err("No data downloaded".to_string());
+        }
+        let mut file = self.open_partition(partition)?;
+        let size = file.metadata().map_err(|e| e.to_string())?.len();
+        write_image(&mut file, size, &self.download)
+    }
+
+    async fn erase(&mut self, partition: &str) -> HandlerResult<()> {
+        if self.locked {
+            return Err("Erasing is not allowed in locked state".to_string());
+        }
+        let file = self.open_partition(partition)?;
+        let size = file.metadata().map_err(|e| e.to_string())?.len();
+        file.set_len(0)
+            .and_then(|_| file.set_len(size))
+            .map_err(|e| e.to_string())
+    }
+
+    async fn set_active(&mut self, slot: &str) -> HandlerResult<()> {
+        let slot = slot.strip_prefix('_').unwrap_or(slot);
+        if !self.has_slots().map_err(|e| e.to_string())? {
+            return Err("Device does not support slots".to_string());
+        }
+        if slot != "a" && slot != "b" {
+            return Err(format!("Invalid slot: {slot}"));
+        }
+        self.current_slot = slot.to_string();
+        Ok(())
+    }
+
+    async fn flashing(&mut self, command: &str) -> HandlerResult<String> {
+        match command {
+            "lock" => self.set_lock(true),
+            "unlock" => self.set_lock(false),
+            "lock_critical" | "unlock_critical" => Ok(String::new()),
+            "get_unlock_ability" => Ok("get_unlock_ability: 1".to_string()),
+            _ => Err(format!("Unknown flashing command: {command}")),
+        }
+    }
+
+    async fn reboot(&mut self, target: Option<&str>) -> HandlerResult<()> {
+        let cmd = match target {
+            Some(target) => format!("reboot-{target}"),
+            None => "reboot".to_string(),
+        };
+        self.reboots.push(cmd);
+        self.download.clear();
+        Ok(())
+    }
+}
+
+/// Write a raw or android sparse image to the start of a partition of `size` bytes
+fn write_image(file: &mut File, size: u64, image: &[u8]) -> Result<(), String> {
+    let header = image
+        .get(..FILE_HEADER_BYTES_LEN)
+        .map(|h| FileHeader::from_bytes(h.try_into().unwrap()));
+    let header = match header {
+        Some(Ok(header)) => header,
+        None | Some(Err(ParseError::UnknownMagic)) => {
+            if image.len() as u64 > size {
+                return Err("Image too large for partition".to_string());
+            }
+            file.write_all(image).map_err(|e| e.to_string())?;
+            return Ok(());
+        }
+        Some(Err(e)) => return Err(format!("Invalid sparse image: {e}")),
+    };
+
+    if header.total_size() as u64 > size {
+        return Err("Image too large for partition".to_string());
+    }
+
+    let truncated = || "Truncated sparse image".to_string();
+    let mut offset = FILE_HEADER_BYTES_LEN;
+    let mut out = 0;
+    for _ in 0..header.chunks {
+        let chunk_bytes = image
+            .get(offset..offset + CHUNK_HEADER_BYTES_LEN)
+            .ok_or_else(truncated)?;
+        let chunk = ChunkHeader::from_bytes(chunk_bytes.try_into().unwrap())
+            .map_err(|e| format!("Invalid sparse image: {e}"))?;
+        offset += CHUNK_HEADER_BYTES_LEN;
+        let data = image
+            .get(offset..offset + chunk.data_size())
+            .ok_or_else(truncated)?;
+        offset += chunk.data_size();
+
+        let out_size = chunk.out_size(&header);
+        // The header's total size can't be trusted to cover the chunks
+        if out + out_size as u64 > size {
+            return Err("Image too large for partition".to_string());
+        }
+        let result = match chunk.chunk_type {
+            ChunkType::Raw if data.len() == out_size => write_at(file, out, data),
+            ChunkType::Fill if data.len() == 4 => {
+                let block: Vec<u8> = data
+                    .iter()
+                    .copied()
+                    .cycle()
+                    .take(header.block_size as usize)
+                    .collect();
+                (0..chunk.chunk_size).try_for_each(|i| {
+                    write_at(file, out + i as u64 * header.block_size as u64, &block)
+                })
+            }
+            ChunkType::DontCare | ChunkType::Crc32 => Ok(()),
+            _ => return Err("Invalid sparse chunk size".to_string()),
+        };
+        result.map_err(|e| e.to_string())?;
+        out += out_size as u64;
+    }
+    Ok(())
+}
+
+fn write_at(file: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+/// In-memory transport connecting a host to a device
+///
+/// Packets and data are passed as-is to the other side of the pair. Used by
+/// [VirtualDevice::serve], but can also connect a [crate::client::FastBoot] client to a
+/// [FastbootServer] with another handler.
+pub struct LoopbackTransport {
+    tx: UnboundedSender<Vec<u8>>,
+    rx: UnboundedReceiver<Vec<u8>>,
+    pending: Vec<u8>,
+}
+
+impl LoopbackTransport {
+    /// Create a pair of connected transports
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = unbounded();
+        let (b_tx, b_rx) = unbounded();
+        let a = Self {
+            tx: a_tx,
+            rx: b_rx,
+            pending: vec![],
+        };
+        let b = Self {
+            tx: b_tx,
+            rx: a_rx,
+            pending: vec![],
+        };
+        (a, b)
+    }
+
+    async fn fill_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            self.pending = self.rx.next().await.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "Other side disconnected")
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl FastbootTransport for LoopbackTransport {
+    async fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.tx
+            .unbounded_send(packet.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Other side disconnected"))
+    }
+
+    async fn receive_packet(&mut self) -> io::Result<Vec<u8>> {
+        self.fill_pending().await?;
+        Ok(std::mem::take(&mut self.pending))
+    }
+
+    async fn read_data(&mut self, max: usize) -> io::Result<Vec<u8>> {
+        self.fill_pending().await?;
+        let rest = self.pending.split_off(max.min(self.pending.len()));
+        Ok(std::mem::replace(&mut self.pending, rest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{DownloadError, FastBoot, FastBootError, Slot, SlotSelection};
+    use android_sparse_image::split::split_image;
+    use tokio::task::JoinHandle;
+
+    const BLOCK_SIZE: u32 = 4096;
+
+    fn device() -> (tempfile::TempDir, VirtualDevice) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut device = VirtualDevice::new(dir.path());
+        device.set_max_download_size(64 * 1024);
+        device.create_partition("boot", 16 * 1024).unwrap();
+        device.create_partition("system", 512 * 1024).unwrap();
+        (dir, device)
+    }
+
+    fn connect(device: VirtualDevice) -> (FastBoot<LoopbackTransport>, JoinHandle<VirtualDevice>) {
+        let (transport, server) = device.serve();
+        let server = tokio::spawn(async move { server.await.unwrap() });
+        (FastBoot::new(transport), server)
+    }
+
+    async fn download(
+        fb: &mut FastBoot<LoopbackTransport>,
+        data: &[u8],
+    ) -> Result<(), DownloadError> {
+        let mut download = fb.download(data.len() as u64).await?;
+        download.extend_from_slice(data).await?;
+        download.finish().await
+    }
+
+    #[tokio::test]
+    async fn getvar() {
+        let (_dir, device) = device();
+        let (mut fb, _server) = connect(device);
+        assert_eq!(fb.get_var("max-download-size").await.unwrap(), "0x00010000");
+        assert_eq!(
+            fb.get_var("partition-size:system").await.unwrap(),
+            "0x0000000000080000"
+        );
+        assert_eq!(fb.get_var("has-slot:system").await.unwrap(), "no");
+        assert_eq!(fb.get_var("slot-count").await.unwrap(), "0");
+        fb.get_var("partition-size:missing").await.unwrap_err();
+        fb.get_var("partition-size:../boot").await.unwrap_err();
+        fb.get_var("has-slot:../boot").await.unwrap_err();
+
+        let vars = fb.get_all_vars().await.unwrap();
+        assert_eq!(vars["version"], "0.4");
+        assert_eq!(vars["partition-size:boot"], "0x0000000000004000");
+    }
+
+    #[tokio::test]
+    async fn flash_raw_and_erase() {
+        let (_dir, device) = device();
+        let path = device.partition_path("boot");
+        let (mut fb, _server) = connect(device);
+        let image: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        download(&mut fb, &image).await.unwrap();
+        fb.flash("boot").await.unwrap();
+        let content = std::fs::read(&path).unwrap();
+        assert_eq!(content.len(), 16 * 1024);
+        assert_eq!(&content[..image.len()], image);
+
+        fb.erase("boot").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![0; 16 * 1024]);
+    }
+
+    #[tokio::test]
+    async fn lock_unlock() {
+        let (_dir, device) = device();
+        let (mut fb, server) = connect(device);
+        fb.flashing_lock().await.unwrap();
+        let state = fb.lock_state().await.unwrap();
+        assert!(!state.unlocked);
+        assert_eq!(state.secure, Some(true));
+
+        download(&mut fb, &[1; 16]).await.unwrap();
+        let err = fb.flash("boot").await.unwrap_err();
+        assert!(matches!(err, FastBootError::DeviceLocked(_)));
+        let err = fb.erase("boot").await.unwrap_err();
+        assert!(matches!(err, FastBootError::DeviceLocked(_)));
+
+        assert!(fb.get_unlock_ability().await.unwrap());
+        fb.flashing_unlock().await.unwrap();
+        fb.flash("boot").await.unwrap();
+
+        drop(fb);
+        assert!(!server.await.unwrap().is_locked());
+    }
+
+    #[tokio::test]
+    async fn download_too_large() {
+        let (_dir, device) = device();
+        let (mut fb, _server) = connect(device);
+        let err = fb.download(64 * 1024 + 1).await.err().unwrap();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+    }
+
+    #[tokio::test]
+    async fn max_download_size_64bit() {
+        let (_dir, mut device) = device();
+        device.set_max_download_size(0x2_0000_0000);
+        let (mut fb, _server) = connect(device);
+        assert_eq!(
+            fb.get_var("max-download-size").await.unwrap(),
+            "0x200000000"
+        );
+        assert_eq!(fb.max_download_size().await.unwrap(), 0x2_0000_0000);
+        let err = fb.download(0x2_0000_0001).await.err().unwrap();
+        assert!(matches!(err, FastBootError::DownloadTooLarge { .. }));
+    }
+
+    #[tokio::test]
+    async fn flash_split_sparse_image() {
+        let (_dir, device) = device();
+        let path = device.partition_path("system");
+        let (mut fb, _server) = connect(device);
+        let header = FileHeader {
+            block_size: BLOCK_SIZE,
+            blocks: 128,
+            chunks: 4,
+            checksum: 0,
+        };
+        let chunks = [
+            ChunkHeader::new_raw(40, BLOCK_SIZE),
+            ChunkHeader::new_fill(8),
+            ChunkHeader::new_dontcare(16),
+            ChunkHeader::new_raw(64, BLOCK_SIZE),
+        ];
+        let mut image = header.to_bytes().to_vec();
+        let mut expected = vec![];
+        for (i, chunk) in chunks.iter().enumerate() {
+            image.extend_from_slice(&chunk.to_bytes());
+            let out_size = chunk.out_size(&header);
+            match chunk.chunk_type {
+                ChunkType::Raw => {
+                    let data: Vec<u8> = (0..out_size).map(|o| (o / 1000 + i) as u8).collect();
+                    image.extend_from_slice(&data);
+                    expected.extend_from_slice(&data);
+                }
+                ChunkType::Fill => {
+                    image.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+                    expected.extend([0xde, 0xad, 0xbe, 0xef].iter().cycle().take(out_size));
+                }
+                _ => expected.extend(std::iter::repeat_n(0, out_size)),
+            }
+        }
+
+        let splits = split_image(&header, &chunks, 64 * 1024).unwrap();
+        assert!(splits.len() > 1);
+        for split in &splits {
+            let mut part = split.header.to_bytes().to_vec();
+            for chunk in &split.chunks {
+                part.extend_from_slice(&chunk.header.to_bytes());
+                part.extend_from_slice(&image[chunk.offset..chunk.offset + chunk.size]);
+            }
+            download(&mut fb, &part).await.unwrap();
+            fb.flash("system").await.unwrap();
+        }
+
+        let content = std::fs::read(path).unwrap();
+        assert_eq!(content, expected);
+    }
+
+    #[tokio::test]
+    async fn sparse_chunks_beyond_partition() {
+        let (_dir, device) = device();
+        let path = device.partition_path("boot");
+        let (mut fb, _server) = connect(device);
+        // The header claims a single block, but the chunk fills more than the partition
+        let header = FileHeader {
+            block_size: BLOCK_SIZE,
+            blocks: 1,
+            chunks: 1,
+            checksum: 0,
+        };
+        let mut image = header.to_bytes().to_vec();
+        image.extend_from_slice(&ChunkHeader::new_fill(8).to_bytes());
+        image.extend_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+
+        download(&mut fb, &image).await.unwrap();
+        let err = fb.flash("boot").await.unwrap_err();
+        assert!(matches!(err, FastBootError::FastbootFailed(_)));
+        assert_eq!(std::fs::metadata(path).unwrap().len(), 16 * 1024);
+    }
+
+    #[tokio::test]
+    async fn slots_and_reboot() {
+        let (_dir, device) = device();
+        device.create_partition("vendor_a", 4096).unwrap();
+        device.create_partition("vendor_b", 4096).unwrap();
+        let (mut fb, server) = connect(device);
+        assert_eq!(fb.get_var("slot-count").await.unwrap(), "2");
+        assert_eq!(fb.get_var("has-slot:vendor").await.unwrap(), "yes");
+        assert_eq!(fb.get_var("current-slot").await.unwrap(), "a");
+        assert_eq!(fb.current_slot().await.unwrap(), Slot::A);
+
+        fb.set_active(Slot::B).await.unwrap();
+        assert_eq!(fb.current_slot().await.unwrap(), Slot::B);
+
+        download(&mut fb, &[0x55; 512]).await.unwrap();
+        fb.flash_slot("vendor", SlotSelection::Other).await.unwrap();
+        fb.reboot_bootloader().await.unwrap();
+
+        // The server stops once the reboot has been acknowledged
+        let device = server.await.unwrap();
+        assert_eq!(device.current_slot(), "b");
+        let vendor_a = std::fs::read(device.partition_path("vendor_a")).unwrap();
+        assert_eq!(&vendor_a[..512], &[0x55; 512]);
+        let vendor_b = std::fs::read(device.partition_path("vendor_b")).unwrap();
+        assert_eq!(vendor_b, [0; 4096]);
+        assert_eq!(device.reboots(), ["reboot-bootloader"]);
+    }
+}